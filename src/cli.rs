@@ -6,7 +6,7 @@ pub struct Cli {
     /// Choose a LLM model
     #[clap(short, long)]
     pub model: Option<String>,
-    /// Choose a role
+    /// Choose a role, optionally with `<variable>=<value>` pairs
     #[clap(short, long)]
     pub role: Option<String>,
     /// Create or reuse a session
@@ -24,7 +24,13 @@ pub struct Cli {
     /// Use light theme
     #[clap(long)]
     pub light_theme: bool,
-    /// Run in dry run mode
+    /// Send large inputs without asking, see `confirm_over_tokens`
+    #[clap(long)]
+    pub yes: bool,
+    /// Print the reply, or the --list-models output, as JSON
+    #[clap(long)]
+    pub json: bool,
+    /// Print the request body instead of sending it
     #[clap(long)]
     pub dry_run: bool,
     /// Print related information
@@ -39,6 +45,9 @@ pub struct Cli {
     /// List all available sessions
     #[clap(long)]
     pub list_sessions: bool,
+    /// Serve an OpenAI-compatible API on the address, `serve_addr` by default
+    #[clap(long, value_name = "ADDR")]
+    pub serve: Option<Option<String>>,
     /// Input text
     text: Vec<String>,
 }
@@ -1,7 +1,7 @@
 use super::openai::{openai_build_body, openai_send_message, openai_send_message_streaming};
 use super::{
-    prompt_input_api_base, prompt_input_api_key, prompt_input_max_token, prompt_input_model_name,
-    Client, ClientConfig, ExtraConfig, ModelInfo, SendData,
+    api_key_in_use, prompt_input_api_base, prompt_input_api_key, prompt_input_max_token,
+    prompt_input_model_name, Client, ClientConfig, ExtraConfig, ModelInfo, SendData,
 };
 
 use crate::config::SharedConfig;
@@ -11,6 +11,7 @@ use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
+use serde_json::Value;
 
 use std::env;
 
@@ -26,7 +27,12 @@ pub struct AzureOpenAIConfig {
     pub name: Option<String>,
     pub api_base: String,
     pub api_key: Option<String>,
+    /// More keys, the next one is used when a request is rate limited
+    #[serde(default)]
+    pub api_keys: Vec<String>,
     pub models: Vec<AzureOpenAIModel>,
+    /// Model used when the client is chosen by its name, the first one if unset
+    pub default_model: Option<String>,
     pub extra: Option<ExtraConfig>,
 }
 
@@ -34,6 +40,8 @@ pub struct AzureOpenAIConfig {
 pub struct AzureOpenAIModel {
     name: String,
     max_tokens: usize,
+    #[serde(default)]
+    vision: bool,
 }
 
 #[async_trait]
@@ -46,11 +54,23 @@ impl Client for AzureOpenAIClient {
         &self.config.extra
     }
 
+    fn client_index(&self) -> usize {
+        self.model_info.index
+    }
+
+    fn build_body(&self, data: SendData) -> Value {
+        openai_build_body(data, self.model_info.name.clone())
+    }
+
     async fn send_message_inner(&self, client: &ReqwestClient, data: SendData) -> Result<String> {
         let builder = self.request_builder(client, data)?;
         openai_send_message(builder).await
     }
 
+    async fn fetch_models_inner(&self, _client: &ReqwestClient) -> Result<Option<Vec<String>>> {
+        Ok(None)
+    }
+
     async fn send_message_streaming_inner(
         &self,
         client: &ReqwestClient,
@@ -91,7 +111,7 @@ impl AzureOpenAIClient {
         local_config
             .models
             .iter()
-            .map(|v| ModelInfo::new(client, &v.name, v.max_tokens, index))
+            .map(|v| ModelInfo::new(client, &v.name, v.max_tokens, v.vision, index))
             .collect()
     }
 
@@ -116,7 +136,7 @@ impl AzureOpenAIClient {
     }
 
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
-        let api_key = self.config.api_key.clone();
+        let api_key = api_key_in_use(&self.global_config.read(), self.model_info.index);
         let api_key = api_key
             .or_else(|| {
                 let env_prefix = match &self.config.name {
@@ -127,7 +147,7 @@ impl AzureOpenAIClient {
             })
             .ok_or_else(|| anyhow!("Miss api_key"))?;
 
-        let body = openai_build_body(data, self.model_info.name.clone());
+        let body = self.build_body(data);
 
         let url = format!(
             "{}/openai/deployments/{}/chat/completions?api-version=2023-05-15",
@@ -1,6 +1,8 @@
-use super::openai::{openai_build_body, openai_send_message, openai_send_message_streaming};
+use super::openai::{
+    openai_build_body, openai_fetch_models, openai_send_message, openai_send_message_streaming,
+};
 use super::{
-    prompt_input_api_base, prompt_input_api_key_optional, prompt_input_max_token,
+    api_key_in_use, prompt_input_api_base, prompt_input_api_key_optional, prompt_input_max_token,
     prompt_input_model_name, Client, ClientConfig, ExtraConfig, ModelInfo, SendData,
 };
 
@@ -11,6 +13,7 @@ use anyhow::Result;
 use async_trait::async_trait;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
+use serde_json::Value;
 use std::env;
 
 #[derive(Debug)]
@@ -25,8 +28,13 @@ pub struct LocalAIConfig {
     pub name: Option<String>,
     pub api_base: String,
     pub api_key: Option<String>,
+    /// More keys, the next one is used when a request is rate limited
+    #[serde(default)]
+    pub api_keys: Vec<String>,
     pub chat_endpoint: Option<String>,
     pub models: Vec<LocalAIModel>,
+    /// Model used when the client is chosen by its name, the first one if unset
+    pub default_model: Option<String>,
     pub extra: Option<ExtraConfig>,
 }
 
@@ -34,6 +42,8 @@ pub struct LocalAIConfig {
 pub struct LocalAIModel {
     name: String,
     max_tokens: usize,
+    #[serde(default)]
+    vision: bool,
 }
 
 #[async_trait]
@@ -46,11 +56,28 @@ impl Client for LocalAIClient {
         &self.config.extra
     }
 
+    fn client_index(&self) -> usize {
+        self.model_info.index
+    }
+
+    fn build_body(&self, data: SendData) -> Value {
+        openai_build_body(data, self.model_info.name.clone())
+    }
+
     async fn send_message_inner(&self, client: &ReqwestClient, data: SendData) -> Result<String> {
         let builder = self.request_builder(client, data)?;
         openai_send_message(builder).await
     }
 
+    async fn fetch_models_inner(&self, client: &ReqwestClient) -> Result<Option<Vec<String>>> {
+        let mut builder = client.get(format!("{}/models", self.config.api_base));
+        if let Some(api_key) = self.api_key() {
+            builder = builder.bearer_auth(api_key);
+        }
+        let models = openai_fetch_models(builder).await?;
+        Ok(Some(models))
+    }
+
     async fn send_message_streaming_inner(
         &self,
         client: &ReqwestClient,
@@ -91,7 +118,7 @@ impl LocalAIClient {
         local_config
             .models
             .iter()
-            .map(|v| ModelInfo::new(client, &v.name, v.max_tokens, index))
+            .map(|v| ModelInfo::new(client, &v.name, v.max_tokens, v.vision, index))
             .collect()
     }
 
@@ -115,14 +142,17 @@ impl LocalAIClient {
         Ok(client_config)
     }
 
-    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
-        let api_key = self.config.api_key.clone();
-        let api_key = api_key.or_else(|| {
+    fn api_key(&self) -> Option<String> {
+        api_key_in_use(&self.global_config.read(), self.model_info.index).or_else(|| {
             let env_prefix = Self::name(&self.config).to_uppercase();
             env::var(format!("{env_prefix}_API_KEY")).ok()
-        });
+        })
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        let api_key = self.api_key();
 
-        let body = openai_build_body(data, self.model_info.name.clone());
+        let body = self.build_body(data);
 
         let chat_endpoint = self
             .config
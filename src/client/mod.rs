@@ -12,17 +12,43 @@ use crate::{
     client::localai::LocalAIClient,
     config::{Config, Message, SharedConfig},
     repl::{ReplyStreamHandler, SharedAbortSignal},
-    utils::tokenize,
+    utils::{redact, tokenize},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
+use crossterm::style::Stylize;
+use futures_util::future::join_all;
 use inquire::{required, Text};
-use reqwest::{Client as ReqwestClient, ClientBuilder, Proxy};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use reqwest::{Client as ReqwestClient, Proxy, Url};
 use serde::Deserialize;
-use std::{env, time::Duration};
+use serde_json::Value;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    env, fmt,
+    future::pending,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{spawn, JoinHandle},
+    time::Duration,
+};
 use tokio::time::sleep;
 
+lazy_static! {
+    /// The position of the key in use for each client, kept for the whole process
+    static ref ACTIVE_API_KEYS: Mutex<HashMap<usize, usize>> = Mutex::new(HashMap::new());
+    /// The webhook posts still running, waited for before exiting
+    static ref WEBHOOKS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(vec![]);
+}
+
+/// Give up on a webhook post after this many seconds
+const WEBHOOK_TIMEOUT: u64 = 10;
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientConfig {
@@ -38,6 +64,8 @@ pub struct ModelInfo {
     pub client: String,
     pub name: String,
     pub max_tokens: usize,
+    /// Whether the model accepts images along with the text
+    pub vision: bool,
     pub index: usize,
 }
 
@@ -48,11 +76,12 @@ impl Default for ModelInfo {
 }
 
 impl ModelInfo {
-    pub fn new(client: &str, name: &str, max_tokens: usize, index: usize) -> Self {
+    pub fn new(client: &str, name: &str, max_tokens: usize, vision: bool, index: usize) -> Self {
         Self {
             client: client.into(),
             name: name.into(),
             max_tokens,
+            vision,
             index,
         }
     }
@@ -61,10 +90,51 @@ impl ModelInfo {
     }
 }
 
+/// Error response returned by the API
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: Option<String>,
+    /// The `code` of the error, such as `model_not_found`
+    pub code: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: u16, message: Option<String>, code: Option<String>) -> Self {
+        Self {
+            status,
+            message,
+            code,
+        }
+    }
+
+    /// Whether the request may succeed when sent again
+    pub fn is_retryable(&self) -> bool {
+        self.status == 429 || (500..600).contains(&self.status)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "Request failed, {message}"),
+            None => write!(f, "Request failed with status {}", self.status),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
 #[derive(Debug)]
 pub struct SendData {
     pub messages: Vec<Message>,
     pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<usize>,
+    pub seed: Option<u64>,
+    pub stop: Vec<String>,
+    /// Data URLs of the images attached to the last message
+    pub images: Vec<String>,
     pub stream: bool,
 }
 #[async_trait]
@@ -80,8 +150,12 @@ pub trait Client {
             .as_ref()
             .and_then(|v| v.connect_timeout)
             .unwrap_or(10);
-        let proxy = options.as_ref().and_then(|v| v.proxy.clone());
-        builder = set_proxy(builder, &proxy)?;
+        let proxy = options.as_ref().and_then(|v| v.proxy.as_deref());
+        let proxy = resolve_proxy(proxy, self.config().read().proxy.as_deref());
+        if let Some(proxy) = proxy {
+            builder = builder
+                .proxy(Proxy::all(&proxy).with_context(|| format!("Invalid proxy `{proxy}`"))?);
+        }
         let client = builder
             .connect_timeout(Duration::from_secs(timeout))
             .build()
@@ -89,18 +163,28 @@ pub trait Client {
         Ok(client)
     }
 
-    fn send_message(&self, content: &str) -> Result<String> {
-        init_tokio_runtime()?.block_on(async {
-            if self.config().read().dry_run {
-                let content = self.config().read().echo_messages(content);
-                return Ok(content);
+    fn send_message(&self, content: &str, abort: SharedAbortSignal) -> Result<String> {
+        let key = self.cache_key(content)?;
+        if let Some(output) = key
+            .as_ref()
+            .and_then(|v| self.config().read().read_cache(v))
+        {
+            return Ok(output);
+        }
+        let output = init_tokio_runtime()?.block_on(async {
+            tokio::select! {
+                ret = send_message_async(self, content) => ret,
+                _ = watch_abort(abort.clone()) => bail!("Aborted"),
+                _ = tokio::signal::ctrl_c() => {
+                    abort.set_ctrlc();
+                    bail!("Aborted")
+                }
             }
-            let client = self.build_client()?;
-            let data = self.config().read().prepare_send_data(content, false)?;
-            self.send_message_inner(&client, data)
-                .await
-                .with_context(|| "Failed to fetch")
-        })
+        })?;
+        if let Some(key) = key {
+            self.config().read().write_cache(&key, &output)?;
+        }
+        Ok(output)
     }
 
     fn send_message_streaming(
@@ -108,20 +192,33 @@ pub trait Client {
         content: &str,
         handler: &mut ReplyStreamHandler,
     ) -> Result<()> {
-        async fn watch_abort(abort: SharedAbortSignal) {
-            loop {
-                if abort.aborted() {
-                    break;
+        async fn watch_timeout(timeout: u64, received: Arc<AtomicBool>) {
+            if timeout > 0 {
+                sleep(Duration::from_secs(timeout)).await;
+                if !received.load(Ordering::SeqCst) {
+                    return;
                 }
-                sleep(Duration::from_millis(100)).await;
             }
+            pending::<()>().await
+        }
+        let key = self.cache_key(content)?;
+        if let Some(output) = key
+            .as_ref()
+            .and_then(|v| self.config().read().read_cache(v))
+        {
+            for token in tokenize(&output) {
+                handler.text(&token)?;
+            }
+            return handler.done();
         }
         let abort = handler.get_abort();
-        init_tokio_runtime()?.block_on(async {
+        let received = handler.get_received();
+        let timeout = self.config().read().request_timeout;
+        let ret = init_tokio_runtime()?.block_on(async {
             tokio::select! {
                 ret = async {
                     if self.config().read().dry_run {
-                        let content = self.config().read().echo_messages(content);
+                        let content = self.echo_request(content, true)?;
                         let tokens = tokenize(&content);
                         for token in tokens {
                             tokio::time::sleep(Duration::from_millis(25)).await;
@@ -130,11 +227,39 @@ pub trait Client {
                         return Ok(());
                     }
                     let client = self.build_client()?;
-                    let data = self.config().read().prepare_send_data(content, true)?;
-                    self.send_message_streaming_inner(&client, handler, data).await
+                    let mut attempt = 0;
+                    let mut rotations = 0;
+                    loop {
+                        let data = self.config().read().prepare_send_data(content, true)?;
+                        // Restarted for every attempt, so the backoff doesn't count against it
+                        let ret = tokio::select! {
+                            ret = self.send_message_streaming_inner(&client, handler, data) => ret,
+                            _ = watch_timeout(timeout, received.clone()) => {
+                                abort.set_ctrlc();
+                                break Err(anyhow!("Request timed out"));
+                            }
+                        };
+                        match ret {
+                            Err(err)
+                                if handler.get_buffer().is_empty()
+                                    && self.should_rotate_api_key(&err, rotations) =>
+                            {
+                                rotations += 1;
+                                self.rotate_api_key();
+                            }
+                            Err(err)
+                                if handler.get_buffer().is_empty()
+                                    && self.should_retry(&err, attempt) =>
+                            {
+                                attempt += 1;
+                                sleep(self.prepare_retry(attempt)).await;
+                            }
+                            ret => break ret.with_context(|| "Failed to fetch stream"),
+                        }
+                    }
                 } => {
                     handler.done()?;
-                    ret.with_context(|| "Failed to fetch stream")
+                    ret
                 }
                 _ = watch_abort(abort.clone()) => {
                     handler.done()?;
@@ -145,11 +270,102 @@ pub trait Client {
                     Ok(())
                 }
             }
+        });
+        if let Some(key) = key {
+            if ret.is_ok() && !abort.aborted() && !handler.get_buffer().is_empty() {
+                self.config()
+                    .read()
+                    .write_cache(&key, handler.get_buffer())?;
+            }
+        }
+        ret
+    }
+
+    /// Name the cached reply after a hash of the model and the request body, `None` if not cached
+    fn cache_key(&self, content: &str) -> Result<Option<String>> {
+        if !self.config().read().use_cache() {
+            return Ok(None);
+        }
+        let data = self.config().read().prepare_send_data(content, false)?;
+        let body = self.build_body(data);
+        let mut hasher = DefaultHasher::new();
+        self.config()
+            .read()
+            .model_info
+            .stringify()
+            .hash(&mut hasher);
+        body.to_string().hash(&mut hasher);
+        Ok(Some(format!("{:016x}", hasher.finish())))
+    }
+
+    /// Fetch the model ids served by the provider, `None` if it has no models endpoint
+    fn fetch_models(&self) -> Result<Option<Vec<String>>> {
+        init_tokio_runtime()?.block_on(async {
+            let client = self.build_client()?;
+            self.fetch_models_inner(&client)
+                .await
+                .with_context(|| "Failed to fetch models")
         })
     }
 
+    /// Build the request body that would be sent to the API
+    fn build_body(&self, data: SendData) -> Value;
+
+    fn echo_request(&self, content: &str, stream: bool) -> Result<String> {
+        let data = self.config().read().prepare_send_data(content, stream)?;
+        let body = self.build_body(data);
+        let output = serde_json::to_string_pretty(&body)
+            .with_context(|| "Unable to echo the request body")?;
+        Ok(format!("```json\n{output}\n```"))
+    }
+
+    fn should_retry(&self, err: &anyhow::Error, attempt: usize) -> bool {
+        attempt < self.config().read().max_retries
+            && err
+                .downcast_ref::<ApiError>()
+                .map(|v| v.is_retryable())
+                .unwrap_or_default()
+    }
+
+    /// The index of the client config, to look up its keys
+    fn client_index(&self) -> usize;
+
+    /// Whether a rate limited request can be resent at once with a key not tried yet
+    fn should_rotate_api_key(&self, err: &anyhow::Error, rotations: usize) -> bool {
+        let rate_limited = err
+            .downcast_ref::<ApiError>()
+            .is_some_and(|v| v.status == 429);
+        let count = client_api_keys(&self.config().read(), self.client_index()).len();
+        rate_limited && rotations + 1 < count
+    }
+
+    fn rotate_api_key(&self) {
+        if rotate_api_key(&self.config().read(), self.client_index()) {
+            eprint!(
+                "{}\r\n",
+                "rate limited, switching to the next api key…".dim()
+            );
+        }
+    }
+
+    /// Print the retry notice and return how long to wait before resending
+    fn prepare_retry(&self, attempt: usize) -> Duration {
+        let (max_retries, base_delay) = {
+            let config = self.config().read();
+            (config.max_retries, config.retry_delay)
+        };
+        let delay = base_delay.saturating_mul(1 << (attempt - 1).min(16));
+        eprint!(
+            "{}\r\n",
+            format!("retrying ({attempt}/{max_retries})…").dim()
+        );
+        Duration::from_millis(delay)
+    }
+
     async fn send_message_inner(&self, client: &ReqwestClient, data: SendData) -> Result<String>;
 
+    async fn fetch_models_inner(&self, client: &ReqwestClient) -> Result<Option<Vec<String>>>;
+
     async fn send_message_streaming_inner(
         &self,
         client: &ReqwestClient,
@@ -158,6 +374,70 @@ pub trait Client {
     ) -> Result<()>;
 }
 
+async fn send_message_async<C: Client + ?Sized>(client: &C, content: &str) -> Result<String> {
+    if client.config().read().dry_run {
+        return client.echo_request(content, false);
+    }
+    let http_client = client.build_client()?;
+    let timeout = client.config().read().request_timeout;
+    let mut attempt = 0;
+    let mut rotations = 0;
+    loop {
+        let data = client.config().read().prepare_send_data(content, false)?;
+        let ret = if timeout > 0 {
+            tokio::time::timeout(
+                Duration::from_secs(timeout),
+                client.send_message_inner(&http_client, data),
+            )
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Request timed out")))
+        } else {
+            client.send_message_inner(&http_client, data).await
+        };
+        match ret {
+            Err(err) if client.should_rotate_api_key(&err, rotations) => {
+                rotations += 1;
+                client.rotate_api_key();
+            }
+            Err(err) if client.should_retry(&err, attempt) => {
+                attempt += 1;
+                sleep(client.prepare_retry(attempt)).await;
+            }
+            ret => break ret.with_context(|| "Failed to fetch"),
+        }
+    }
+}
+
+/// Send the content to all clients at once, `None` if aborted before they all replied
+pub fn send_message_concurrently(
+    clients: &[Box<dyn Client>],
+    content: &str,
+    abort: SharedAbortSignal,
+) -> Result<Option<Vec<Result<String>>>> {
+    init_tokio_runtime()?.block_on(async {
+        let requests = clients
+            .iter()
+            .map(|client| send_message_async(client.as_ref(), content));
+        tokio::select! {
+            replies = join_all(requests) => Ok(Some(replies)),
+            _ = watch_abort(abort.clone()) => Ok(None),
+            _ = tokio::signal::ctrl_c() => {
+                abort.set_ctrlc();
+                Ok(None)
+            }
+        }
+    })
+}
+
+async fn watch_abort(abort: SharedAbortSignal) {
+    loop {
+        if abort.aborted() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct ExtraConfig {
     pub proxy: Option<String>,
@@ -165,6 +445,9 @@ pub struct ExtraConfig {
 }
 
 pub fn init_client(config: SharedConfig) -> Result<Box<dyn Client>> {
+    if let Some(api_base) = current_api_base(&config.read()) {
+        Url::parse(&api_base).with_context(|| format!("Invalid api_base '{api_base}'"))?;
+    }
     OpenAIClient::init(config.clone())
         .or_else(|| LocalAIClient::init(config.clone()))
         .or_else(|| AzureOpenAIClient::init(config.clone()))
@@ -211,6 +494,128 @@ pub fn list_models(config: &Config) -> Vec<ModelInfo> {
         .collect()
 }
 
+/// Where the requests of the current model are sent
+pub fn current_api_base(config: &Config) -> Option<String> {
+    match config.clients.get(config.model_info.index)? {
+        ClientConfig::OpenAI(c) => Some(OpenAIClient::api_base(c)),
+        ClientConfig::LocalAI(c) => Some(c.api_base.clone()),
+        ClientConfig::AzureOpenAI(c) => Some(c.api_base.clone()),
+    }
+}
+
+/// The `api_key` and then the `api_keys` of a client
+fn client_api_keys(config: &Config, index: usize) -> Vec<String> {
+    let (api_key, api_keys) = match config.clients.get(index) {
+        Some(ClientConfig::OpenAI(c)) => (&c.api_key, &c.api_keys),
+        Some(ClientConfig::LocalAI(c)) => (&c.api_key, &c.api_keys),
+        Some(ClientConfig::AzureOpenAI(c)) => (&c.api_key, &c.api_keys),
+        None => return vec![],
+    };
+    api_key.iter().chain(api_keys).cloned().collect()
+}
+
+/// The model of the client at `index` used when only the client is named
+pub fn client_default_model(config: &Config, index: usize) -> Option<String> {
+    match config.clients.get(index)? {
+        ClientConfig::OpenAI(c) => c.default_model.clone(),
+        ClientConfig::LocalAI(c) => c.default_model.clone(),
+        ClientConfig::AzureOpenAI(c) => c.default_model.clone(),
+    }
+}
+
+/// Whether the API rejected the request because it does not serve the model
+pub fn is_model_unavailable(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|v| v.downcast_ref::<ApiError>())
+        .any(|v| {
+            if v.code.as_deref() == Some("model_not_found") {
+                return true;
+            }
+            let not_found = v.message.as_deref().is_some_and(|v| {
+                let v = v.to_lowercase();
+                v.contains("model") && (v.contains("not found") || v.contains("does not exist"))
+            });
+            matches!(v.status, 400 | 404) && not_found
+        })
+}
+
+/// Send through a client of the current model, moving along `model_fallbacks` while it is unavailable
+pub fn send_with_fallback<T>(
+    config: &SharedConfig,
+    mut send: impl FnMut(&dyn Client) -> Result<T>,
+) -> Result<T> {
+    let mut tried = vec![];
+    loop {
+        let client = init_client(config.clone())?;
+        let err = match send(client.as_ref()) {
+            Err(err) if is_model_unavailable(&err) => err,
+            ret => return ret,
+        };
+        let model = config.read().model_info.stringify();
+        tried.push(model.clone());
+        match config.write().fall_back_model(&tried)? {
+            Some(next) => {
+                let warning = format!("Warning: {model} is unavailable, falling back to {next}");
+                eprintln!("{}", warning.yellow());
+            }
+            None => return Err(err),
+        }
+    }
+}
+
+/// The configured key that the client at `index` sends, `None` if it has none
+pub fn api_key_in_use(config: &Config, index: usize) -> Option<String> {
+    let keys = client_api_keys(config, index);
+    if keys.is_empty() {
+        return None;
+    }
+    let position = ACTIVE_API_KEYS
+        .lock()
+        .get(&index)
+        .copied()
+        .unwrap_or_default();
+    Some(keys[position % keys.len()].clone())
+}
+
+/// Move the client at `index` on to its next key, false if it has only one
+pub fn rotate_api_key(config: &Config, index: usize) -> bool {
+    let count = client_api_keys(config, index).len();
+    if count < 2 {
+        return false;
+    }
+    let mut active = ACTIVE_API_KEYS.lock();
+    let position = active.entry(index).or_default();
+    *position = (*position + 1) % count;
+    true
+}
+
+/// The redacted key of the current model, with its position when there are several
+pub fn current_api_key(config: &Config) -> Option<String> {
+    let index = config.model_info.index;
+    let key = redact(&api_key_in_use(config, index)?);
+    let count = client_api_keys(config, index).len();
+    if count < 2 {
+        return Some(key);
+    }
+    let position = ACTIVE_API_KEYS
+        .lock()
+        .get(&index)
+        .copied()
+        .unwrap_or_default();
+    Some(format!("{key} ({}/{count})", position % count + 1))
+}
+
+/// The proxy the requests of the current model go through
+pub fn current_proxy(config: &Config) -> Option<String> {
+    let extra = match config.clients.get(config.model_info.index)? {
+        ClientConfig::OpenAI(c) => &c.extra,
+        ClientConfig::LocalAI(c) => &c.extra,
+        ClientConfig::AzureOpenAI(c) => &c.extra,
+    };
+    let proxy = extra.as_ref().and_then(|v| v.proxy.as_deref());
+    resolve_proxy(proxy, config.proxy.as_deref())
+}
+
 pub(crate) fn init_tokio_runtime() -> Result<tokio::runtime::Runtime> {
     tokio::runtime::Builder::new_current_thread()
         .enable_all()
@@ -255,18 +660,67 @@ pub(crate) fn prompt_op_err<T>(_: T) -> anyhow::Error {
     anyhow!("An error happened, try again later.")
 }
 
-fn set_proxy(builder: ClientBuilder, proxy: &Option<String>) -> Result<ClientBuilder> {
-    let proxy = if let Some(proxy) = proxy {
-        if proxy.is_empty() || proxy == "false" || proxy == "-" {
-            return Ok(builder);
-        }
-        proxy.clone()
-    } else if let Ok(proxy) = env::var("HTTPS_PROXY").or_else(|_| env::var("ALL_PROXY")) {
-        proxy
-    } else {
-        return Ok(builder);
+/// Post the record to `webhook_url` from another thread, a failure is only a warning
+pub fn post_webhook(config: &Config, record: Value) {
+    let url = match config.webhook_url.as_ref() {
+        Some(v) => v.clone(),
+        None => return,
     };
-    let builder =
-        builder.proxy(Proxy::all(&proxy).with_context(|| format!("Invalid proxy `{proxy}`"))?);
-    Ok(builder)
+    let secret = config.webhook_secret.clone();
+    let proxy = resolve_proxy(None, config.proxy.as_deref());
+    let handle = spawn(move || {
+        if let Err(err) = send_webhook(&url, secret.as_deref(), proxy.as_deref(), &record) {
+            let warning = format!("Warning: Failed to post to the webhook, {err:#}");
+            eprintln!("{}", warning.yellow());
+        }
+    });
+    let mut webhooks = WEBHOOKS.lock();
+    webhooks.retain(|v| !v.is_finished());
+    webhooks.push(handle);
+}
+
+/// Wait for the webhook posts still running, so that exiting doesn't cut them off
+pub fn wait_webhooks() {
+    let webhooks = std::mem::take(&mut *WEBHOOKS.lock());
+    for handle in webhooks {
+        let _ = handle.join();
+    }
+}
+
+fn send_webhook(
+    url: &str,
+    secret: Option<&str>,
+    proxy: Option<&str>,
+    record: &Value,
+) -> Result<()> {
+    let mut builder = ReqwestClient::builder().timeout(Duration::from_secs(WEBHOOK_TIMEOUT));
+    if let Some(proxy) = proxy {
+        builder =
+            builder.proxy(Proxy::all(proxy).with_context(|| format!("Invalid proxy `{proxy}`"))?);
+    }
+    let client = builder.build().with_context(|| "Failed to build client")?;
+    init_tokio_runtime()?.block_on(async {
+        let mut request = client.post(url).json(record);
+        if let Some(secret) = secret {
+            request = request.header("X-Webhook-Secret", secret);
+        }
+        let status = request.send().await?.status();
+        if !status.is_success() {
+            bail!("{url} responded with {status}");
+        }
+        Ok(())
+    })
+}
+
+/// The proxy of the client, then the global one, then HTTPS_PROXY/ALL_PROXY
+fn resolve_proxy(proxy: Option<&str>, global_proxy: Option<&str>) -> Option<String> {
+    let proxy = proxy.or(global_proxy).map(|v| v.to_string()).or_else(|| {
+        ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+            .into_iter()
+            .find_map(|v| env::var(v).ok())
+    })?;
+    if proxy.is_empty() || proxy == "false" || proxy == "-" {
+        return None;
+    }
+    Some(proxy)
 }
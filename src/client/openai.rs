@@ -1,4 +1,7 @@
-use super::{prompt_input_api_key, Client, ClientConfig, ExtraConfig, ModelInfo, SendData};
+use super::{
+    api_key_in_use, prompt_input_api_key, ApiError, Client, ClientConfig, ExtraConfig, ModelInfo,
+    SendData,
+};
 
 use crate::config::SharedConfig;
 use crate::repl::ReplyStreamHandler;
@@ -7,7 +10,7 @@ use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
 use eventsource_stream::Eventsource;
 use futures_util::StreamExt;
-use reqwest::{Client as ReqwestClient, RequestBuilder};
+use reqwest::{Client as ReqwestClient, RequestBuilder, Response};
 use serde::Deserialize;
 use serde_json::{json, Value};
 use std::env;
@@ -25,7 +28,13 @@ pub struct OpenAIClient {
 pub struct OpenAIConfig {
     pub name: Option<String>,
     pub api_key: Option<String>,
+    /// More keys, the next one is used when a request is rate limited
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    pub api_base: Option<String>,
     pub organization_id: Option<String>,
+    /// Model used when the client is chosen by its name, the first one if unset
+    pub default_model: Option<String>,
     pub extra: Option<ExtraConfig>,
 }
 
@@ -39,11 +48,28 @@ impl Client for OpenAIClient {
         &self.config.extra
     }
 
+    fn client_index(&self) -> usize {
+        self.model_info.index
+    }
+
+    fn build_body(&self, data: SendData) -> Value {
+        openai_build_body(data, self.model_info.name.clone())
+    }
+
     async fn send_message_inner(&self, client: &ReqwestClient, data: SendData) -> Result<String> {
         let builder = self.request_builder(client, data)?;
         openai_send_message(builder).await
     }
 
+    async fn fetch_models_inner(&self, client: &ReqwestClient) -> Result<Option<Vec<String>>> {
+        let (api_base, api_key) = self.credentials()?;
+        let builder = client
+            .get(format!("{api_base}/models"))
+            .bearer_auth(api_key);
+        let models = openai_fetch_models(builder).await?;
+        Ok(Some(models))
+    }
+
     async fn send_message_streaming_inner(
         &self,
         client: &ReqwestClient,
@@ -82,13 +108,14 @@ impl OpenAIClient {
         let client = Self::name(local_config);
 
         [
-            ("gpt-3.5-turbo", 4096),
-            ("gpt-3.5-turbo-16k", 16384),
-            ("gpt-4", 8192),
-            ("gpt-4-32k", 32768),
+            ("gpt-3.5-turbo", 4096, false),
+            ("gpt-3.5-turbo-16k", 16384, false),
+            ("gpt-4", 8192, false),
+            ("gpt-4-32k", 32768, false),
+            ("gpt-4-vision-preview", 128000, true),
         ]
         .into_iter()
-        .map(|(name, max_tokens)| ModelInfo::new(client, name, max_tokens, index))
+        .map(|(name, max_tokens, vision)| ModelInfo::new(client, name, max_tokens, vision, index))
         .collect()
     }
 
@@ -101,19 +128,35 @@ impl OpenAIClient {
         Ok(client_config)
     }
 
-    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+    /// The configured api_base, then the `<NAME>_API_BASE` env var, then the official one
+    pub fn api_base(local_config: &OpenAIConfig) -> String {
+        let env_prefix = Self::name(local_config).to_uppercase();
+        local_config
+            .api_base
+            .clone()
+            .or_else(|| env::var(format!("{env_prefix}_API_BASE")).ok())
+            .unwrap_or_else(|| API_BASE.to_string())
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    fn credentials(&self) -> Result<(String, String)> {
         let env_prefix = Self::name(&self.config).to_uppercase();
 
-        let api_key = self.config.api_key.clone();
+        let api_key = api_key_in_use(&self.global_config.read(), self.model_info.index);
         let api_key = api_key
             .or_else(|| env::var(format!("{env_prefix}_API_KEY")).ok())
             .ok_or_else(|| anyhow!("Miss api_key"))?;
 
-        let body = openai_build_body(data, self.model_info.name.clone());
+        let api_base = Self::api_base(&self.config);
 
-        let api_base = env::var(format!("{env_prefix}_API_BASE"))
-            .ok()
-            .unwrap_or_else(|| API_BASE.to_string());
+        Ok((api_base, api_key))
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        let (api_base, api_key) = self.credentials()?;
+
+        let body = self.build_body(data);
 
         let url = format!("{api_base}/chat/completions");
 
@@ -128,55 +171,153 @@ impl OpenAIClient {
 }
 
 pub(crate) async fn openai_send_message(builder: RequestBuilder) -> Result<String> {
-    let data: Value = builder.send().await?.json().await?;
+    let res = check_response(builder.send().await?).await?;
+    let data: Value = res.json().await?;
     if let Some(err_msg) = data["error"]["message"].as_str() {
         bail!("Request failed, {err_msg}");
     }
 
-    let output = data["choices"][0]["message"]["content"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Unexpected response {data}"))?;
+    let message = &data["choices"][0]["message"];
+    let mut tool_calls = vec![];
+    merge_tool_calls(&mut tool_calls, message);
+    let mut output = match message["content"].as_str() {
+        Some(v) => v.to_string(),
+        None if !tool_calls.is_empty() => String::new(),
+        None => bail!("Unexpected response {data}"),
+    };
+    for call in &tool_calls {
+        output.push_str(&call.render(output.is_empty()));
+    }
 
-    Ok(output.to_string())
+    Ok(output)
 }
 
 pub(crate) async fn openai_send_message_streaming(
     builder: RequestBuilder,
     handler: &mut ReplyStreamHandler,
 ) -> Result<()> {
-    let res = builder.send().await?;
-    if !res.status().is_success() {
-        let data: Value = res.json().await?;
-        if let Some(err_msg) = data["error"]["message"].as_str() {
-            bail!("Request failed, {err_msg}");
-        }
-        bail!("Request failed");
-    }
+    let res = check_response(builder.send().await?).await?;
+    // eventsource keeps a character that is split between two chunks until it is whole
     let mut stream = res.bytes_stream().eventsource();
+    let mut tool_calls = vec![];
     while let Some(part) = stream.next().await {
         let chunk = part?.data;
         if chunk == "[DONE]" {
             break;
         }
         let data: Value = serde_json::from_str(&chunk)?;
-        let text = data["choices"][0]["delta"]["content"]
-            .as_str()
-            .unwrap_or_default();
+        let delta = &data["choices"][0]["delta"];
+        merge_tool_calls(&mut tool_calls, delta);
+        let text = delta["content"].as_str().unwrap_or_default();
         if text.is_empty() {
             continue;
         }
         handler.text(text)?;
     }
+    for call in &tool_calls {
+        let block = call.render(handler.get_buffer().is_empty());
+        handler.text(&block)?;
+    }
 
     Ok(())
 }
 
+/// A tool call, or a legacy function call, put together from the deltas of a stream
+#[derive(Debug, Default, PartialEq)]
+struct ToolCall {
+    name: String,
+    arguments: String,
+}
+
+impl ToolCall {
+    /// Show the call as a block of its own, the arguments pretty-printed if they are valid JSON
+    fn render(&self, first: bool) -> String {
+        let arguments = serde_json::from_str::<Value>(&self.arguments)
+            .ok()
+            .and_then(|v| serde_json::to_string_pretty(&v).ok())
+            .unwrap_or_else(|| self.arguments.clone());
+        let separator = if first { "" } else { "\n\n" };
+        format!(
+            "{separator}**Tool call** `{}`\n```json\n{arguments}\n```\n",
+            self.name
+        )
+    }
+}
+
+/// Add the `tool_calls` or `function_call` of a delta or message to the calls so far
+fn merge_tool_calls(calls: &mut Vec<ToolCall>, delta: &Value) {
+    let mut merge = |index: usize, function: &Value| {
+        if calls.len() <= index {
+            calls.resize_with(index + 1, Default::default);
+        }
+        let call = &mut calls[index];
+        if let Some(name) = function["name"].as_str() {
+            call.name.push_str(name);
+        }
+        if let Some(arguments) = function["arguments"].as_str() {
+            call.arguments.push_str(arguments);
+        }
+    };
+    if let Some(tool_calls) = delta["tool_calls"].as_array() {
+        for (i, tool_call) in tool_calls.iter().enumerate() {
+            let index = tool_call["index"].as_u64().map(|v| v as usize).unwrap_or(i);
+            merge(index, &tool_call["function"]);
+        }
+    } else if delta["function_call"].is_object() {
+        merge(0, &delta["function_call"]);
+    }
+}
+
+pub(crate) async fn openai_fetch_models(builder: RequestBuilder) -> Result<Vec<String>> {
+    let res = check_response(builder.send().await?).await?;
+    let data: Value = res.json().await?;
+    let models = data["data"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Unexpected response {data}"))?;
+    let mut ids: Vec<String> = models
+        .iter()
+        .filter_map(|v| v["id"].as_str().map(|v| v.to_string()))
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
+
+async fn check_response(res: Response) -> Result<Response> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let text = res.text().await.unwrap_or_default();
+    let data = serde_json::from_str::<Value>(&text).unwrap_or_default();
+    let message = data["error"]["message"].as_str().map(|v| v.to_string());
+    let code = data["error"]["code"].as_str().map(|v| v.to_string());
+    Err(ApiError::new(status.as_u16(), message, code).into())
+}
+
 pub(crate) fn openai_build_body(data: SendData, model: String) -> Value {
     let SendData {
         messages,
         temperature,
+        top_p,
+        max_tokens,
+        seed,
+        stop,
+        images,
         stream,
     } = data;
+    let mut messages = json!(messages);
+    if !images.is_empty() {
+        // Images go along with the text of the last message, in the vision format
+        if let Some(message) = messages.as_array_mut().and_then(|v| v.last_mut()) {
+            let mut content = vec![json!({ "type": "text", "text": message["content"] })];
+            content.extend(
+                images
+                    .iter()
+                    .map(|url| json!({ "type": "image_url", "image_url": { "url": url } })),
+            );
+            message["content"] = json!(content);
+        }
+    }
     let mut body = json!({
         "model": model,
         "messages": messages,
@@ -187,9 +328,82 @@ pub(crate) fn openai_build_body(data: SendData, model: String) -> Value {
             .and_then(|m| m.insert("temperature".into(), json!(v)));
     }
 
+    if let Some(v) = top_p {
+        body.as_object_mut()
+            .and_then(|m| m.insert("top_p".into(), json!(v)));
+    }
+
+    if let Some(v) = max_tokens {
+        body.as_object_mut()
+            .and_then(|m| m.insert("max_tokens".into(), json!(v)));
+    }
+
+    if let Some(v) = seed {
+        body.as_object_mut()
+            .and_then(|m| m.insert("seed".into(), json!(v)));
+    }
+
+    if !stop.is_empty() {
+        body.as_object_mut()
+            .and_then(|m| m.insert("stop".into(), json!(stop)));
+    }
+
     if stream {
         body.as_object_mut()
             .and_then(|m| m.insert("stream".into(), json!(true)));
     }
     body
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::init_tokio_runtime;
+
+    #[test]
+    fn test_merge_tool_calls() {
+        let mut calls = vec![];
+        let deltas = [
+            json!({"tool_calls": [{"index": 0, "function": {"name": "get_weather", "arguments": ""}}]}),
+            json!({"tool_calls": [{"index": 0, "function": {"arguments": "{\"city\":"}}]}),
+            json!({"tool_calls": [{"index": 0, "function": {"arguments": "\"Paris\"}"}}]}),
+            json!({"tool_calls": [{"index": 1, "function": {"name": "get_time", "arguments": "{}"}}]}),
+            json!({"content": "ignored"}),
+        ];
+        for delta in &deltas {
+            merge_tool_calls(&mut calls, delta);
+        }
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "get_weather");
+        assert_eq!(calls[0].arguments, r#"{"city":"Paris"}"#);
+        assert_eq!(
+            calls[0].render(true),
+            "**Tool call** `get_weather`\n```json\n{\n  \"city\": \"Paris\"\n}\n```\n"
+        );
+
+        let mut calls = vec![];
+        merge_tool_calls(
+            &mut calls,
+            &json!({"function_call": {"name": "f", "arguments": "oops"}}),
+        );
+        assert_eq!(
+            calls[0].render(false),
+            "\n\n**Tool call** `f`\n```json\noops\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_split_utf8_chunks() {
+        let data = "data: 你好😀\n\n".as_bytes();
+        // Cut inside 好 and inside 😀
+        let chunks = [&data[..10], &data[10..14], &data[14..]];
+        let events = init_tokio_runtime().unwrap().block_on(async {
+            futures_util::stream::iter(chunks.map(Ok::<_, std::io::Error>))
+                .eventsource()
+                .map(|v| v.unwrap().data)
+                .collect::<Vec<_>>()
+                .await
+        });
+        assert_eq!(events, ["你好😀"]);
+    }
+}
@@ -25,6 +25,16 @@ pub enum MessageRole {
     User,
 }
 
+impl MessageRole {
+    pub fn stringify(&self) -> &str {
+        match self {
+            MessageRole::System => "system",
+            MessageRole::Assistant => "assistant",
+            MessageRole::User => "user",
+        }
+    }
+}
+
 pub fn num_tokens_from_messages(messages: &[Message]) -> usize {
     let mut num_tokens = 0;
     for message in messages.iter() {
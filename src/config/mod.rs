@@ -1,34 +1,45 @@
 mod message;
 mod role;
 mod session;
+mod template;
 
-pub use self::message::Message;
+pub use self::message::{Message, MessageRole};
 use self::role::Role;
-use self::session::{Session, TEMP_SESSION_NAME};
+use self::session::{session_slug, Session, TEMP_SESSION_NAME};
+use self::template::fill_template;
+pub use self::template::parse_template_text;
 
 use crate::client::openai::{OpenAIClient, OpenAIConfig};
 use crate::client::{
-    create_client_config, list_client_types, list_models, prompt_op_err, ClientConfig, ExtraConfig,
+    client_default_model, create_client_config, current_api_base, current_api_key, current_proxy,
+    list_client_types, list_models, post_webhook, prompt_op_err, ClientConfig, ExtraConfig,
     ModelInfo, SendData,
 };
 use crate::config::message::num_tokens_from_messages;
 use crate::render::RenderOptions;
-use crate::utils::{get_env_name, light_theme_from_colorfgbg, now};
+use crate::utils::{
+    closest_matches, count_tokens, expand_env_vars, get_env_name, light_theme_from_colorfgbg, now,
+    pipe_text, redact, write_atomic,
+};
 
 use anyhow::{anyhow, bail, Context, Result};
-use inquire::{Confirm, Select, Text};
+use chrono::{DateTime, Local};
+use crossterm::style::Stylize;
+use inquire::{required, Confirm, Select, Text};
 use is_terminal::IsTerminal;
 use parking_lot::RwLock;
 use serde::Deserialize;
+use serde_json::json;
 use std::{
+    collections::{BTreeMap, HashMap},
     env,
-    fs::{create_dir_all, read_dir, read_to_string, remove_file, File, OpenOptions},
-    io::{stdout, Write},
+    fs::{create_dir_all, read_dir, read_to_string, remove_file, rename, write, File, OpenOptions},
+    io::{stdin, stdout, Write},
     path::{Path, PathBuf},
     process::exit,
     sync::Arc,
 };
-use syntect::highlighting::ThemeSet;
+use syntect::highlighting::{Theme, ThemeSet};
 
 /// Monokai Extended
 const DARK_THEME: &[u8] = include_bytes!("../../assets/monokai-extended.theme.bin");
@@ -36,17 +47,60 @@ const LIGHT_THEME: &[u8] = include_bytes!("../../assets/monokai-extended-light.t
 
 const CONFIG_FILE_NAME: &str = "config.yaml";
 const ROLES_FILE_NAME: &str = "roles.yaml";
+const MACROS_FILE_NAME: &str = "macros.yaml";
+const TEMPLATES_FILE_NAME: &str = "templates.yaml";
 const MESSAGES_FILE_NAME: &str = "messages.md";
 const SESSIONS_DIR_NAME: &str = "sessions";
+const CACHE_DIR_NAME: &str = "cache";
+const HISTORY_FILE_NAME: &str = "history.txt";
+const THEMES_DIR_NAME: &str = "themes";
+const LAST_SESSION_FILE_NAME: &str = "last_session";
+const MAX_RECENT_MODELS: usize = 10;
+/// The temperature the APIs use when none is given
+pub const DEFAULT_TEMPERATURE: f64 = 1.0;
+
+/// Appended to a reply stopped by the user, so that follow-ups know it is incomplete
+const TRUNCATED_MARK: &str = "\n\n[The reply was stopped by the user]";
 
-const SET_COMPLETIONS: [&str; 7] = [
+pub const SUMMARIZE_PROMPT: &str = "Summarize the conversation so far in a concise paragraph. \
+Keep every fact, decision and open question needed to continue it.";
+
+const SET_COMPLETIONS: [&str; 35] = [
     ".set temperature",
+    ".set top_p",
+    ".set max_tokens",
+    ".set seed",
     ".set save true",
     ".set save false",
     ".set highlight true",
     ".set highlight false",
+    ".set no_color true",
+    ".set no_color false",
     ".set dry_run true",
     ".set dry_run false",
+    ".set theme",
+    ".set render_math true",
+    ".set render_math false",
+    ".set hyperlinks true",
+    ".set hyperlinks false",
+    ".set squeeze_blank true",
+    ".set squeeze_blank false",
+    ".set use_pager true",
+    ".set use_pager false",
+    ".set stream true",
+    ".set stream false",
+    ".set stream_flush_interval",
+    ".set show_stream_stats true",
+    ".set show_stream_stats false",
+    ".set show_reading_time true",
+    ".set show_reading_time false",
+    ".set max_retries",
+    ".set request_timeout",
+    ".set cache_ttl",
+    ".set confirm_over_tokens",
+    ".set auto_name_session true",
+    ".set auto_name_session false",
+    ".set prompt_format",
 ];
 
 #[derive(Debug, Clone, Deserialize)]
@@ -54,30 +108,117 @@ const SET_COMPLETIONS: [&str; 7] = [
 pub struct Config {
     /// LLM model
     pub model: Option<String>,
+    /// Models tried in turn when the current one is unknown or unavailable, such as `openai:gpt-4`
+    pub model_fallbacks: Vec<String>,
     /// GPT temperature, between 0 and 2
     #[serde(rename(serialize = "temperature", deserialize = "temperature"))]
     pub default_temperature: Option<f64>,
+    /// Nucleus sampling probability mass, between 0 and 1
+    #[serde(rename(serialize = "top_p", deserialize = "top_p"))]
+    pub default_top_p: Option<f64>,
+    /// Limit the tokens of each reply, capped at the max tokens of the model
+    #[serde(rename(serialize = "max_tokens", deserialize = "max_tokens"))]
+    pub default_max_tokens: Option<usize>,
+    /// Limit of the replies of each model, such as `openai:gpt-4: 1000`, instead of `max_tokens`
+    pub model_max_tokens: HashMap<String, usize>,
+    /// Sent along to make the replies reproducible, for the models that support it
+    pub seed: Option<u64>,
+    /// Sequences where the model stops generating, roles can override it
+    pub stop: Vec<String>,
+    /// Prompt put before the one of the role in every request, such as personal preferences
+    pub system_prompt: Option<String>,
+    /// Role used on startup and restored by `.exit role`
+    pub default_role: Option<String>,
+    /// Text put before every input that is sent, the saved input stays as typed
+    pub prompt_prefix: Option<String>,
+    /// Text put after every input that is sent
+    pub prompt_suffix: Option<String>,
     /// Whether to save the message
     pub save: bool,
+    /// Append every exchange as a JSON line to this file
+    pub log_file: Option<PathBuf>,
+    /// POST every exchange as JSON to this URL, in the shape of the log lines
+    pub webhook_url: Option<String>,
+    /// Sent in the `X-Webhook-Secret` header of the webhook posts
+    pub webhook_secret: Option<String>,
     /// Whether to disable highlight
     pub highlight: bool,
+    /// Render plain text without escape codes, also on when `NO_COLOR` is set or stdout isn't a tty
+    pub no_color: bool,
     /// Used only for debugging
     pub dry_run: bool,
     /// Whether to use a light theme
     pub light_theme: bool,
+    /// Syntax highlighting theme, a builtin one or a .tmTheme file in the themes dir
+    pub theme: Option<String>,
     /// Specify the text-wrapping mode (no, auto, <max-width>)
     pub wrap: Option<String>,
     /// Whether wrap code block
     pub wrap_code: bool,
+    /// Render `$...$` math with Unicode symbols
+    pub render_math: bool,
+    /// Make links clickable in terminals that support it, otherwise show them as `text (url)`
+    pub hyperlinks: bool,
+    /// Collapse runs of blank lines in replies into one, code blocks are kept as they are
+    pub squeeze_blank: bool,
     /// Automatically copy the last output to the clipboard
     pub auto_copy: bool,
+    /// Copy through the OSC 52 escape sequence when the system clipboard is unavailable
+    pub osc52_copy: bool,
+    /// Whether to page replies that exceed the terminal height
+    pub use_pager: bool,
+    /// Set false to wait for the whole reply and render it at once
+    pub stream: bool,
+    /// Milliseconds to gather streamed chunks before printing them, 0 prints each one
+    pub stream_flush_interval: u64,
+    /// Repaint the line being streamed in command mode, as the REPL does
+    pub live_render: bool,
+    /// Address that `--serve` listens on
+    pub serve_addr: String,
+    /// Print token statistics after each streamed reply
+    pub show_stream_stats: bool,
+    /// Print the word count and reading time after long streamed replies
+    pub show_reading_time: bool,
+    /// Read every finished reply aloud with this command, such as `say` or `espeak`
+    pub tts_command: Option<String>,
+    /// Pipe every reply through this shell command and keep its output, which turns off streaming.
+    /// `.compress` summaries are not filtered
+    pub reply_filter: Option<String>,
+    /// How many times to resend a request that failed with 429 or 5xx
+    pub max_retries: usize,
+    /// Base delay in milliseconds between retries, doubled on every attempt
+    pub retry_delay: u64,
+    /// Give up on a request after this many seconds without a token, 0 to disable it
+    pub request_timeout: u64,
+    /// Seconds to reuse the reply of an identical request, 0 to disable the cache
+    pub cache_ttl: u64,
+    /// Ask before sending an input that exceeds the context of the model, instead of only warning
+    pub confirm_overflow: bool,
+    /// Ask before sending an input of more tokens than this, 0 to disable it
+    pub confirm_over_tokens: usize,
+    /// Name a temp session after its first input, instead of asking for a name when it's saved
+    pub auto_name_session: bool,
+    /// Proxy for the requests of all clients, such as `socks5://127.0.0.1:1080`
+    pub proxy: Option<String>,
     /// REPL keybindings. values: emacs, vi
     pub keybindings: Keybindings,
+    /// How many REPL inputs to keep in the history file, 0 to disable it
+    pub max_history: usize,
+    /// REPL prompt with `{role}`, `{model}`, `{client}` and `{session}` placeholders, such as `[{role}:{model}]`
+    pub prompt_format: Option<String>,
+    /// Prices of the models per 1k tokens, keyed by model name such as `openai:gpt-4`
+    pub pricing: HashMap<String, ModelPricing>,
     /// Setup AIs
     pub clients: Vec<ClientConfig>,
     /// Predefined roles
     #[serde(skip)]
     pub roles: Vec<Role>,
+    /// Named lists of REPL lines, run with `.macro run <name>`
+    #[serde(skip)]
+    pub macros: BTreeMap<String, Vec<String>>,
+    /// Named prompts with an `{{input}}` slot, used with `.template use <name>`
+    #[serde(skip)]
+    pub templates: BTreeMap<String, String>,
     /// Current selected role
     #[serde(skip)]
     pub role: Option<Role>,
@@ -88,30 +229,97 @@ pub struct Config {
     pub model_info: ModelInfo,
     #[serde(skip)]
     pub last_message: Option<(String, String)>,
+    /// Models selected lately, the most recent first
+    #[serde(skip)]
+    pub recent_models: Vec<String>,
+    /// Data URLs of the images to send with the next input
+    #[serde(skip)]
+    pub images: Vec<String>,
+    /// The reply before the last one, for `.diff`
+    #[serde(skip)]
+    pub previous_reply: Option<String>,
+    /// Send without asking, set by `--yes`
+    #[serde(skip)]
+    pub assume_yes: bool,
     #[serde(skip)]
     pub temperature: Option<f64>,
+    #[serde(skip)]
+    pub top_p: Option<f64>,
+    #[serde(skip)]
+    pub max_tokens: Option<usize>,
+    /// Set with `.set max_tokens`, it then applies to every model
+    #[serde(skip)]
+    pub max_tokens_override: Option<usize>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             model: None,
+            model_fallbacks: vec![],
             default_temperature: None,
+            default_top_p: None,
+            default_max_tokens: None,
+            model_max_tokens: HashMap::new(),
+            seed: None,
+            stop: vec![],
+            system_prompt: None,
+            default_role: None,
+            prompt_prefix: None,
+            prompt_suffix: None,
             save: true,
             highlight: true,
+            no_color: false,
             dry_run: false,
+            log_file: None,
+            webhook_url: None,
+            webhook_secret: None,
             light_theme: false,
+            theme: None,
             wrap: None,
             wrap_code: false,
+            render_math: false,
+            hyperlinks: true,
+            squeeze_blank: false,
             auto_copy: false,
+            osc52_copy: false,
+            use_pager: false,
+            stream: true,
+            stream_flush_interval: 0,
+            live_render: false,
+            serve_addr: "127.0.0.1:8000".into(),
+            show_stream_stats: false,
+            show_reading_time: false,
+            tts_command: None,
+            reply_filter: None,
+            max_retries: 0,
+            retry_delay: 1000,
+            request_timeout: 0,
+            cache_ttl: 0,
+            confirm_overflow: false,
+            confirm_over_tokens: 0,
+            auto_name_session: false,
+            proxy: None,
             keybindings: Default::default(),
+            max_history: 1000,
+            prompt_format: None,
+            pricing: HashMap::new(),
             clients: vec![ClientConfig::OpenAI(OpenAIConfig::default())],
             roles: vec![],
+            macros: BTreeMap::new(),
+            templates: BTreeMap::new(),
             role: None,
             session: None,
             model_info: Default::default(),
             last_message: None,
+            previous_reply: None,
+            assume_yes: false,
+            recent_models: vec![],
+            images: vec![],
             temperature: None,
+            top_p: None,
+            max_tokens: None,
+            max_tokens_override: None,
         }
     }
 }
@@ -144,26 +352,71 @@ impl Config {
         }
 
         config.temperature = config.default_temperature;
+        config.top_p = config.default_top_p;
 
         config.set_model_info()?;
         config.merge_env_vars();
         config.load_roles()?;
+        config.load_macros()?;
+        config.load_templates()?;
         config.ensure_sessions_dir()?;
         config.detect_theme()?;
 
+        if let Some(name) = config.default_role.clone() {
+            if let Err(err) = config.set_role(&name, &[]) {
+                eprintln!(
+                    "{}",
+                    format!("Warning: Unable to use the default role, {err}").yellow()
+                );
+            }
+        }
+
+        if let Some(name) = config.theme.clone() {
+            if Self::load_theme(&name)?.is_none() {
+                eprintln!(
+                    "{}",
+                    format!("Warning: Unknown theme `{name}`, fall back to the default").yellow()
+                );
+                config.theme = None;
+            }
+        }
+
         Ok(config)
     }
 
+    /// Find a role by name, or read the prompt of `@<path>` as a role named after the file
     pub fn retrieve_role(&self, name: &str) -> Result<Role> {
-        self.roles
-            .iter()
-            .find(|v| v.match_name(name))
-            .map(|v| {
-                let mut role = v.clone();
+        if let Some(path) = name.strip_prefix('@') {
+            let prompt = read_to_string(path)
+                .with_context(|| format!("Unable to read role prompt '{path}'"))?;
+            if prompt.trim().is_empty() {
+                bail!("Empty role prompt '{path}'");
+            }
+            let name = Path::new(path)
+                .file_stem()
+                .map(|v| v.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.to_string());
+            return Ok(Role::new(&name, prompt.trim()));
+        }
+        match self.roles.iter().find(|v| v.match_name(name)) {
+            Some(role) => {
+                let mut role = role.clone();
                 role.complete_prompt_args(name);
-                role
-            })
-            .ok_or_else(|| anyhow!("Unknown role `{name}`"))
+                Ok(role)
+            }
+            None => bail!("{}", self.unknown_role_message(name)),
+        }
+    }
+
+    /// Suggest the roles with a similar name, or all of them when none is close
+    fn unknown_role_message(&self, name: &str) -> String {
+        let names: Vec<&str> = self.roles.iter().map(|v| v.name.as_str()).collect();
+        if names.is_empty() {
+            return format!("Unknown role '{name}', no roles are defined");
+        }
+        let matches = closest_matches(name, &names);
+        let available = if matches.is_empty() { names } else { matches };
+        format!("Unknown role '{name}'. Available: {}", available.join(", "))
     }
 
     pub fn config_dir() -> Result<PathBuf> {
@@ -184,15 +437,31 @@ impl Config {
         Ok(path)
     }
 
-    pub fn save_message(&mut self, input: &str, output: &str) -> Result<()> {
+    pub fn save_message(&mut self, input: &str, output: &str, truncated: bool) -> Result<()> {
+        self.previous_reply = self.last_message.take().map(|(_, v)| v);
         self.last_message = Some((input.to_string(), output.to_string()));
 
         if self.dry_run {
             return Ok(());
         }
 
+        let output = if truncated && !output.is_empty() {
+            format!("{output}{TRUNCATED_MARK}")
+        } else {
+            output.to_string()
+        };
+        let output = output.as_str();
+
+        if let Err(err) = self.report_exchange(input, output) {
+            eprintln!("{}", format!("Warning: {err:#}").yellow());
+        }
+
         if let Some(session) = self.session.as_mut() {
+            let first = session.is_empty() && session.is_temp();
             session.add_message(input, output)?;
+            if first && self.auto_name_session {
+                self.name_session_after(input);
+            }
             return Ok(());
         }
 
@@ -219,6 +488,144 @@ impl Config {
             .with_context(|| "Failed to save message")
     }
 
+    /// Append the exchange to the log file and post it to the webhook, whichever are set
+    fn report_exchange(&self, input: &str, output: &str) -> Result<()> {
+        if self.log_file.is_none() && self.webhook_url.is_none() {
+            return Ok(());
+        }
+        let input_tokens = num_tokens_from_messages(&self.build_messages(input)?);
+        let record = json!({
+            "timestamp": now(),
+            "model": self.model_info.stringify(),
+            "role": self.role.as_ref().map(|v| &v.name),
+            "session": self.session.as_ref().map(|v| &v.name),
+            "input": input,
+            "output": output,
+            "input_tokens": input_tokens,
+            "output_tokens": count_tokens(output),
+        });
+        post_webhook(self, record.clone());
+        let path = match self.log_file.as_ref() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        ensure_parent_exists(path)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to create/append {}", path.display()))?;
+        writeln!(file, "{record}").with_context(|| "Failed to write log")
+    }
+
+    /// Take the last exchange out of the session so that it can be resent
+    pub fn discard_last_message(&mut self) -> Option<Vec<Message>> {
+        let (_, output) = self.last_message.as_ref()?;
+        let session = self.session.as_mut()?;
+        match session.messages.last() {
+            Some(message)
+                if message
+                    .content
+                    .strip_suffix(TRUNCATED_MARK)
+                    .unwrap_or(&message.content)
+                    == output =>
+            {
+                session.pop_message()
+            }
+            _ => None,
+        }
+    }
+
+    /// Replace the older messages of the session with a summary, returning the tokens after it
+    pub fn compress_session(&mut self, summary: &str, keep: usize) -> Result<usize> {
+        match self.session.as_mut() {
+            Some(session) => {
+                session.compress(summary, keep);
+                Ok(session.tokens)
+            }
+            None => bail!("No session"),
+        }
+    }
+
+    pub fn pin_message(&mut self, turn: usize, pinned: bool) -> Result<()> {
+        match self.session.as_mut() {
+            Some(session) => session.pin(turn, pinned),
+            None => bail!("No session"),
+        }
+    }
+
+    pub fn search_session(&self, pattern: &str) -> Result<String> {
+        let session = match self.session.as_ref() {
+            Some(v) => v,
+            None => bail!("No session"),
+        };
+        let matches = session.search(pattern);
+        if matches.is_empty() {
+            bail!("No matches for '{pattern}'");
+        }
+        let output = matches
+            .into_iter()
+            .map(|(turn, role, snippet)| {
+                let role = match role {
+                    MessageRole::User => "user",
+                    _ => "assistant",
+                };
+                format!("{turn:>4}  {role:<10}{snippet}")
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        Ok(output)
+    }
+
+    pub fn vars_info(&self) -> Result<String> {
+        let session = match self.session.as_ref() {
+            Some(v) => v,
+            None => bail!("No session"),
+        };
+        let mut output = String::new();
+        for (name, value) in &session.variables {
+            output.push_str(&format!("{name:<20}{value}\n"));
+        }
+        Ok(output)
+    }
+
+    pub fn set_session_variable(&mut self, name: &str, value: Option<&str>) -> Result<()> {
+        match self.session.as_mut() {
+            Some(session) => session.set_variable(name, value),
+            None => bail!("No session"),
+        }
+        Ok(())
+    }
+
+    /// Forget the messages of the session but keep it and its role
+    pub fn clear_context(&mut self) -> Result<()> {
+        match self.session.as_mut() {
+            Some(session) => session.clear_messages(),
+            None => bail!("No session"),
+        }
+        self.last_message = None;
+        self.previous_reply = None;
+        Ok(())
+    }
+
+    pub fn undo_message(&mut self) -> Result<()> {
+        let session = match self.session.as_mut() {
+            Some(v) => v,
+            None => bail!("No session"),
+        };
+        if session.pop_message().is_none() {
+            bail!("Nothing to undo")
+        }
+        self.last_message = session.last_exchange();
+        Ok(())
+    }
+
+    pub fn restore_messages(&mut self, messages: Vec<Message>) {
+        if let Some(session) = self.session.as_mut() {
+            session.restore_messages(messages);
+        }
+    }
+
     pub fn config_file() -> Result<PathBuf> {
         Self::local_path(CONFIG_FILE_NAME)
     }
@@ -231,35 +638,311 @@ impl Config {
         )
     }
 
+    pub fn macros_file() -> Result<PathBuf> {
+        Self::local_path(MACROS_FILE_NAME)
+    }
+
+    pub fn templates_file() -> Result<PathBuf> {
+        Self::local_path(TEMPLATES_FILE_NAME)
+    }
+
     pub fn messages_file() -> Result<PathBuf> {
         Self::local_path(MESSAGES_FILE_NAME)
     }
 
+    pub fn history_file() -> Result<PathBuf> {
+        Self::local_path(HISTORY_FILE_NAME)
+    }
+
+    pub fn themes_dir() -> Result<PathBuf> {
+        Self::local_path(THEMES_DIR_NAME)
+    }
+
     pub fn sessions_dir() -> Result<PathBuf> {
         Self::local_path(SESSIONS_DIR_NAME)
     }
 
+    pub fn cache_dir() -> Result<PathBuf> {
+        Self::local_path(CACHE_DIR_NAME)
+    }
+
+    /// Whether replies are streamed, a reply filter needs the whole reply at once
+    pub fn use_stream(&self) -> bool {
+        self.stream && self.reply_filter.is_none()
+    }
+
+    /// Pass the reply through `reply_filter`, if one is set
+    pub fn filter_reply(&self, reply: &str) -> Result<String> {
+        match &self.reply_filter {
+            Some(command) => {
+                pipe_text(command, reply).with_context(|| "Failed to apply the reply_filter")
+            }
+            None => Ok(reply.to_string()),
+        }
+    }
+
+    /// Whether replies are cached, only requests at temperature 0 are expected not to vary
+    pub fn use_cache(&self) -> bool {
+        self.cache_ttl > 0
+            && !self.dry_run
+            && self.get_temperature().unwrap_or(DEFAULT_TEMPERATURE) == 0.0
+    }
+
+    /// The cached reply of the key, unless it is older than `cache_ttl`
+    pub fn read_cache(&self, key: &str) -> Option<String> {
+        let path = Self::cache_dir().ok()?.join(key);
+        let age = path.metadata().ok()?.modified().ok()?.elapsed().ok()?;
+        if age.as_secs() >= self.cache_ttl {
+            return None;
+        }
+        read_to_string(path).ok()
+    }
+
+    pub fn write_cache(&self, key: &str, reply: &str) -> Result<()> {
+        let path = Self::cache_dir()?.join(key);
+        ensure_parent_exists(&path)?;
+        write(&path, reply).with_context(|| format!("Failed to write to {}", path.display()))
+    }
+
     pub fn session_file(name: &str) -> Result<PathBuf> {
         let mut path = Self::sessions_dir()?;
         path.push(&format!("{name}.yaml"));
         Ok(path)
     }
 
-    pub fn set_role(&mut self, name: &str) -> Result<()> {
-        let role = self.retrieve_role(name)?;
+    /// The name of the last used session, if it still exists
+    pub fn last_session() -> Option<String> {
+        let path = Self::local_path(LAST_SESSION_FILE_NAME).ok()?;
+        let name = read_to_string(path).ok()?.trim().to_string();
+        if name.is_empty() || !Self::session_file(&name).ok()?.exists() {
+            return None;
+        }
+        Some(name)
+    }
+
+    pub fn set_role(&mut self, name: &str, variables: &[(String, String)]) -> Result<()> {
+        let mut role = self.retrieve_role(name)?;
+        role.set_variables(variables)?;
         if let Some(session) = self.session.as_mut() {
             session.update_role(Some(role.clone()))?;
         }
-        self.temperature = role.temperature;
+        self.temperature = role.temperature.or(self.default_temperature);
+        self.top_p = role.top_p.or(self.default_top_p);
         self.role = Some(role);
         Ok(())
     }
 
+    /// Persist the active role with the current sampling params under a new name
+    pub fn save_role(&mut self, name: &str, force: bool) -> Result<()> {
+        let mut current = match self.role.clone() {
+            Some(v) => v,
+            None => bail!("No role"),
+        };
+        // Saved from its definition, so the placeholders filled in for this run are kept
+        let mut role = self
+            .roles
+            .iter()
+            .find(|v| v.name == current.name)
+            .cloned()
+            .unwrap_or_else(|| current.clone());
+        role.name = name.to_string();
+        role.temperature = self.temperature;
+        role.top_p = self.top_p;
+        match self.roles.iter().position(|v| v.name == name) {
+            Some(_) if !force => {
+                bail!("Role '{name}' already exists, use --force to overwrite")
+            }
+            Some(index) => self.roles[index] = role,
+            None => self.roles.push(role),
+        }
+        self.save_roles()?;
+        current.name = name.to_string();
+        current.temperature = self.temperature;
+        current.top_p = self.top_p;
+        self.role = Some(current);
+        Ok(())
+    }
+
+    /// Copy the definition of a role under a new name
+    pub fn clone_role(&mut self, base: &str, name: &str) -> Result<()> {
+        let mut role = match self.roles.iter().find(|v| v.name == base) {
+            Some(v) => v.clone(),
+            None => bail!("{}", self.unknown_role_message(base)),
+        };
+        if self.roles.iter().any(|v| v.name == name) {
+            bail!("Role '{name}' already exists");
+        }
+        role.name = name.to_string();
+        self.roles.push(role);
+        self.save_roles()
+    }
+
+    /// The definition of a role as yaml, for editing it
+    pub fn role_definition(&self, name: &str) -> Result<String> {
+        match self.roles.iter().find(|v| v.name == name) {
+            Some(role) => role.info(),
+            None => bail!("{}", self.unknown_role_message(name)),
+        }
+    }
+
+    /// Replace the definition of a role, nothing is changed if the yaml is invalid
+    pub fn edit_role(&mut self, name: &str, content: &str) -> Result<()> {
+        let index = match self.roles.iter().position(|v| v.name == name) {
+            Some(v) => v,
+            None => bail!("{}", self.unknown_role_message(name)),
+        };
+        let role: Role =
+            serde_yaml::from_str(content).with_context(|| "Invalid role, nothing is changed")?;
+        if role.name.trim().is_empty() {
+            bail!("Invalid role, the name is empty");
+        }
+        if role.name != name && self.roles.iter().any(|v| v.name == role.name) {
+            bail!("Role '{}' already exists", role.name);
+        }
+        self.roles[index] = role;
+        self.save_roles()
+    }
+
+    fn save_roles(&self) -> Result<()> {
+        let path = Self::roles_file()?;
+        ensure_parent_exists(&path)?;
+        let content = serde_yaml::to_string(&self.roles).with_context(|| "Unable to save roles")?;
+        write_atomic(&path, content)
+            .with_context(|| format!("Failed to save roles to {}", path.display()))
+    }
+
+    pub fn get_macro(&self, name: &str) -> Result<Vec<String>> {
+        match self.macros.get(name) {
+            Some(lines) => {
+                guard_macro_lines(lines)?;
+                Ok(lines.clone())
+            }
+            None => bail!("Unknown macro '{name}'"),
+        }
+    }
+
+    /// The prompt of a template with the input filled in
+    pub fn apply_template(&self, name: &str, input: &str) -> Result<String> {
+        match self.templates.get(name) {
+            Some(template) => Ok(fill_template(template, input)),
+            None => bail!("Unknown template '{name}'"),
+        }
+    }
+
+    pub fn templates_info(&self) -> String {
+        self.templates
+            .iter()
+            .map(|(name, text)| format!("{name:<20}{}", text.replace('\n', "\\n")))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Save a template, or remove it if the text is empty
+    pub fn save_template(&mut self, name: &str, text: &str) -> Result<()> {
+        if text.trim().is_empty() {
+            self.templates.remove(name);
+        } else {
+            self.templates.insert(name.to_string(), text.to_string());
+        }
+        let path = Self::templates_file()?;
+        ensure_parent_exists(&path)?;
+        let content =
+            serde_yaml::to_string(&self.templates).with_context(|| "Unable to save templates")?;
+        write_atomic(&path, content)
+            .with_context(|| format!("Failed to save templates to {}", path.display()))
+    }
+
+    pub fn macros_info(&self) -> String {
+        self.macros
+            .iter()
+            .map(|(name, lines)| format!("{name:<20}{}", lines.join("; ")))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Save the lines as a macro, or remove the macro if there are none
+    pub fn save_macro(&mut self, name: &str, lines: Vec<String>) -> Result<()> {
+        guard_macro_lines(&lines)?;
+        if lines.is_empty() {
+            self.macros.remove(name);
+        } else {
+            self.macros.insert(name.to_string(), lines);
+        }
+        let path = Self::macros_file()?;
+        ensure_parent_exists(&path)?;
+        let content =
+            serde_yaml::to_string(&self.macros).with_context(|| "Unable to save macros")?;
+        write_atomic(&path, content)
+            .with_context(|| format!("Failed to save macros to {}", path.display()))
+    }
+
+    /// Ask for the role variables that have no value yet
+    pub fn fill_role_variables(&mut self) -> Result<()> {
+        let session_role = self.session.as_ref().and_then(|v| v.role.as_ref());
+        let mut names: Vec<String> = vec![];
+        for role in [self.role.as_ref(), session_role].into_iter().flatten() {
+            for name in role.missing_variables() {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        if names.is_empty() {
+            return Ok(());
+        }
+        if !stdin().is_terminal() {
+            bail!("Missing value for variable `{}` of the role", names[0]);
+        }
+        let mut values = vec![];
+        for name in names {
+            let value = Text::new(&format!("{name}:"))
+                .with_validator(required!("This field is required"))
+                .prompt()
+                .map_err(prompt_op_err)?;
+            values.push((name, value));
+        }
+        let session_role = self.session.as_mut().and_then(|v| v.role.as_mut());
+        for role in [self.role.as_mut(), session_role].into_iter().flatten() {
+            for (name, value) in &values {
+                role.set_variable(name, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Go back to the default role, or to no role when it is already in use
+    pub fn exit_role(&mut self) -> Result<()> {
+        match self.default_role.clone() {
+            Some(name) if self.role.as_ref().map(|v| v.name.as_str()) != Some(name.as_str()) => {
+                self.set_role(&name, &[])
+            }
+            _ => self.clear_role(),
+        }
+    }
+
+    /// Change the default role and save it to the config file
+    pub fn set_default_role(&mut self, name: Option<&str>) -> Result<()> {
+        if let Some(name) = name {
+            if name.starts_with('@') {
+                bail!("The default role must be defined in the roles file");
+            }
+            self.retrieve_role(name)?;
+        }
+        let value = match name {
+            Some(name) => serde_json::to_string(name)?,
+            None => "null".into(),
+        };
+        update_config_file("default_role", &value)?;
+        self.default_role = name.map(|v| v.to_string());
+        Ok(())
+    }
+
     pub fn clear_role(&mut self) -> Result<()> {
         if let Some(session) = self.session.as_mut() {
             session.update_role(None)?;
         }
         self.temperature = self.default_temperature;
+        self.top_p = self.default_top_p;
         self.role = None;
         Ok(())
     }
@@ -276,18 +959,77 @@ impl Config {
         Ok(())
     }
 
-    pub fn echo_messages(&self, content: &str) -> String {
-        if let Some(session) = self.session.as_ref() {
-            session.echo_messages(content)
-        } else if let Some(role) = self.role.as_ref() {
-            role.echo_messages(content)
-        } else {
-            content.to_string()
+    pub fn get_top_p(&self) -> Option<f64> {
+        self.top_p
+    }
+
+    pub fn set_top_p(&mut self, value: Option<f64>) -> Result<()> {
+        self.top_p = value;
+        if let Some(session) = self.session.as_mut() {
+            session.top_p = value;
         }
+        Ok(())
+    }
+
+    /// The stop sequences of the role in use, or the global ones
+    pub fn get_stop(&self) -> Vec<String> {
+        let session_role = self.session.as_ref().and_then(|v| v.role.as_ref());
+        self.role
+            .as_ref()
+            .or(session_role)
+            .and_then(|v| v.stop.clone())
+            .unwrap_or_else(|| self.stop.clone())
+    }
+
+    /// Apply `.set max_tokens`, or else the limit configured for the model
+    fn apply_max_tokens(&mut self) -> Result<()> {
+        let value = self
+            .max_tokens_override
+            .or_else(|| {
+                self.model_max_tokens
+                    .get(&self.model_info.stringify())
+                    .copied()
+            })
+            .or(self.default_max_tokens);
+        self.set_max_tokens(value)
+    }
+
+    /// Clamps the value to the max tokens of the model, with a warning
+    pub fn set_max_tokens(&mut self, value: Option<usize>) -> Result<()> {
+        let limit = self.model_info.max_tokens;
+        self.max_tokens = match value {
+            Some(0) => bail!("Invalid value, max_tokens must be greater than 0"),
+            Some(v) if v > limit => {
+                let warning = format!(
+                    "Warning: max_tokens {v} exceeds the {limit} tokens of {}, use {limit} instead",
+                    self.model_info.stringify()
+                );
+                eprintln!("{}", warning.yellow());
+                Some(limit)
+            }
+            v => v,
+        };
+        Ok(())
+    }
+
+    /// Role info with the temperature and top_p in effect
+    pub fn role_info(&self) -> Result<String> {
+        let mut role = self.role.clone().ok_or_else(|| anyhow!("No role"))?;
+        role.temperature = self.temperature;
+        role.top_p = self.top_p;
+        role.info()
     }
 
     pub fn build_messages(&self, content: &str) -> Result<Vec<Message>> {
-        let messages = if let Some(session) = self.session.as_ref() {
+        let role = match self.session.as_ref() {
+            Some(session) => session.role.as_ref(),
+            None => self.role.as_ref(),
+        };
+        if let Some(name) = role.and_then(|v| v.missing_variables().into_iter().next()) {
+            bail!("Missing value for variable `{name}` of the role");
+        }
+        let content = &self.wrap_prompt(content);
+        let mut messages = if let Some(session) = self.session.as_ref() {
             session.build_emssages(content)
         } else if let Some(role) = self.role.as_ref() {
             role.build_messages(content)
@@ -295,14 +1037,84 @@ impl Config {
             let message = Message::new(content);
             vec![message]
         };
-        let tokens = num_tokens_from_messages(&messages);
-        if tokens >= self.model_info.max_tokens {
-            bail!("Exceed max tokens limit")
+        // Not part of the session, so changing it applies to the saved sessions as well
+        if let Some(prompt) = self.system_prompt.as_ref().filter(|v| !v.trim().is_empty()) {
+            messages.insert(
+                0,
+                Message {
+                    role: MessageRole::System,
+                    content: prompt.clone(),
+                },
+            );
         }
-
         Ok(messages)
     }
 
+    /// Put `prompt_prefix` and `prompt_suffix` around the input, each on its own line.
+    /// An empty input, as built for `.info` and `.context`, stays empty
+    pub fn wrap_prompt(&self, input: &str) -> String {
+        if input.trim().is_empty() {
+            return input.to_string();
+        }
+        let mut parts = vec![];
+        if let Some(prefix) = self.prompt_prefix.as_ref().filter(|v| !v.trim().is_empty()) {
+            parts.push(prefix.as_str());
+        }
+        parts.push(input);
+        if let Some(suffix) = self.prompt_suffix.as_ref().filter(|v| !v.trim().is_empty()) {
+            parts.push(suffix.as_str());
+        }
+        parts.join("\n")
+    }
+
+    /// Tokens of everything sent along with the input
+    pub fn input_tokens(&self, input: &str) -> Result<usize> {
+        Ok(num_tokens_from_messages(&self.build_messages(input)?))
+    }
+
+    /// Whether to send the input, warning or asking first if it won't fit in the context
+    pub fn check_context_window(&self, input: &str) -> Result<bool> {
+        let tokens = self.input_tokens(input)?;
+        let max_tokens = self.model_info.max_tokens;
+        if tokens >= max_tokens {
+            let message = format!(
+                "The input takes {tokens} tokens, exceeding the {max_tokens} tokens context of {}",
+                self.model_info.stringify()
+            );
+            if !self.confirm_overflow {
+                eprintln!("{}", format!("Warning: {message}").yellow());
+            } else if !stdin().is_terminal() {
+                bail!("{message}");
+            } else if !Confirm::new(&format!("{message}, send anyway?"))
+                .with_default(false)
+                .prompt()?
+            {
+                return Ok(false);
+            }
+        }
+        self.check_token_budget(tokens)
+    }
+
+    /// Ask before sending more than `confirm_over_tokens` tokens, unless `--yes` is given
+    fn check_token_budget(&self, tokens: usize) -> Result<bool> {
+        if self.confirm_over_tokens == 0 || tokens <= self.confirm_over_tokens || self.assume_yes {
+            return Ok(true);
+        }
+        let cost = self
+            .pricing
+            .get(&self.model_info.stringify())
+            .map(|v| format!(" (~${:.4})", tokens as f64 * v.input / 1000.0))
+            .unwrap_or_default();
+        let message = format!("This will send {tokens} tokens{cost}");
+        if !stdin().is_terminal() {
+            bail!("{message}, pass --yes to send it anyway");
+        }
+        let ans = Confirm::new(&format!("{message}. Continue?"))
+            .with_default(false)
+            .prompt()?;
+        Ok(ans)
+    }
+
     pub fn set_wrap(&mut self, value: &str) -> Result<()> {
         if value == "no" {
             self.wrap = None;
@@ -318,14 +1130,97 @@ impl Config {
     }
 
     pub fn set_model(&mut self, value: &str) -> Result<()> {
+        self.switch_model(value)?;
+        let name = self.model_info.stringify();
+        self.recent_models.retain(|v| v != &name);
+        self.recent_models.insert(0, name);
+        self.recent_models.truncate(MAX_RECENT_MODELS);
+        Ok(())
+    }
+
+    /// Switch to the first of `model_fallbacks` that is known and not tried yet, returning its name.
+    /// A session locked to its model never falls back, and attached images need a vision model
+    pub fn fall_back_model(&mut self, tried: &[String]) -> Result<Option<String>> {
+        if self.guard_model_lock().is_err() {
+            return Ok(None);
+        }
+        let models = list_models(self);
+        let next = self
+            .model_fallbacks
+            .iter()
+            .find(|v| {
+                !tried.contains(v)
+                    && models
+                        .iter()
+                        .any(|m| &&m.stringify() == v && (self.images.is_empty() || m.vision))
+            })
+            .cloned();
+        if let Some(name) = &next {
+            self.set_model(name)?;
+        }
+        Ok(next)
+    }
+
+    /// Fail if the session is locked to its model
+    pub fn guard_model_lock(&self) -> Result<()> {
+        if let Some(session) = self.session.as_ref().filter(|v| v.locked) {
+            bail!(
+                "Session '{}' is locked to {}, use `.model <name> --force` to switch anyway",
+                session.name,
+                session.model
+            );
+        }
+        Ok(())
+    }
+
+    pub fn lock_session(&mut self, locked: bool) -> Result<()> {
+        match self.session.as_mut() {
+            Some(session) => {
+                session.set_locked(locked);
+                Ok(())
+            }
+            None => bail!("No session"),
+        }
+    }
+
+    /// Move to the next recent model, or the previous one, keeping the order of the list
+    pub fn cycle_model(&mut self, forward: bool) -> Result<()> {
+        let len = self.recent_models.len();
+        if len < 2 {
+            bail!("No other recent model");
+        }
+        let current = self.model_info.stringify();
+        let index = self
+            .recent_models
+            .iter()
+            .position(|v| v == &current)
+            .unwrap_or_default();
+        let index = if forward {
+            (index + 1) % len
+        } else {
+            (index + len - 1) % len
+        };
+        let name = self.recent_models[index].clone();
+        self.switch_model(&name)
+    }
+
+    fn switch_model(&mut self, value: &str) -> Result<()> {
         let models = list_models(self);
         let mut model_info = None;
         if value.contains(':') {
             if let Some(model) = models.iter().find(|v| v.stringify() == value) {
                 model_info = Some(model.clone());
             }
-        } else if let Some(model) = models.iter().find(|v| v.client == value) {
-            model_info = Some(model.clone());
+        } else {
+            let mut client_models = models.iter().filter(|v| v.client == value).peekable();
+            let default_model = client_models
+                .peek()
+                .and_then(|v| client_default_model(self, v.index));
+            model_info = match default_model {
+                Some(name) => client_models.find(|v| v.name == name),
+                None => client_models.next(),
+            }
+            .cloned();
         }
         match model_info {
             None => bail!("Unknown model '{}'", value),
@@ -334,6 +1229,7 @@ impl Config {
                     session.set_model(&model_info.stringify())?;
                 }
                 self.model_info = model_info;
+                self.apply_max_tokens()?;
                 Ok(())
             }
         }
@@ -347,6 +1243,100 @@ impl Config {
         tokens
     }
 
+    /// The system messages that the next request will carry
+    pub fn prompt_info(&self) -> Result<String> {
+        let messages = self.build_messages("")?;
+        let prompts: Vec<&str> = messages
+            .iter()
+            .filter(|v| matches!(v.role, MessageRole::System))
+            .map(|v| v.content.as_str())
+            .collect();
+        if prompts.is_empty() {
+            match self.role.as_ref() {
+                Some(role) if role.embedded() => {
+                    bail!("No system prompt, the role prompt is merged into the input")
+                }
+                _ => bail!("No system prompt"),
+            }
+        }
+        Ok(prompts.join("\n\n"))
+    }
+
+    /// Every message the next request will carry before the input, tagged with its role
+    pub fn context_info(&self) -> Result<String> {
+        let mut messages = self.build_messages("")?;
+        // The message that the input would fill
+        if messages
+            .last()
+            .is_some_and(|v| matches!(v.role, MessageRole::User) && v.content.trim().is_empty())
+        {
+            messages.pop();
+        }
+        if messages.is_empty() {
+            bail!("No context, the next request carries the input alone");
+        }
+        let output = messages
+            .iter()
+            .map(|v| format!("[{}]\n{}", v.role.stringify(), v.content.trim_end()))
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        Ok(output)
+    }
+
+    pub fn token_usage(&self) -> String {
+        let tokens = match self.session.as_ref() {
+            Some(session) => session.tokens,
+            None => {
+                let messages = match self.role.as_ref() {
+                    Some(role) => role.build_messages(""),
+                    None => vec![Message::new("")],
+                };
+                num_tokens_from_messages(&messages)
+            }
+        };
+        let max_tokens = self.model_info.max_tokens;
+        let items = vec![
+            ("tokens", tokens.to_string()),
+            ("max_tokens", max_tokens.to_string()),
+            (
+                "remain_tokens",
+                max_tokens.saturating_sub(tokens).to_string(),
+            ),
+        ];
+        let mut output = String::new();
+        for (name, value) in items {
+            output.push_str(&format!("{name:<20}{value}\n"));
+        }
+        output
+    }
+
+    pub fn cost(&self) -> Result<String> {
+        let session = match self.session.as_ref() {
+            Some(v) => v,
+            None => bail!("No session"),
+        };
+        let (input_tokens, output_tokens) = session.usage();
+        let cost = match self.pricing.get(&session.model) {
+            Some(pricing) => format!(
+                "${:.4}",
+                (input_tokens as f64 * pricing.input + output_tokens as f64 * pricing.output)
+                    / 1000.0
+            ),
+            None => "pricing unavailable".into(),
+        };
+        let items = vec![
+            ("model", session.model.clone()),
+            ("input_tokens", input_tokens.to_string()),
+            ("output_tokens", output_tokens.to_string()),
+            ("cost", cost),
+        ];
+        let mut output = String::new();
+        for (name, value) in items {
+            output.push_str(&format!("{name:<20}{value}\n"));
+        }
+        Ok(output)
+    }
+
     pub fn info(&self) -> Result<String> {
         let path_info = |path: &Path| {
             let state = if path.exists() { "" } else { " ⚠️" };
@@ -355,57 +1345,185 @@ impl Config {
         let temperature = self
             .temperature
             .map_or_else(|| String::from("-"), |v| v.to_string());
+        let top_p = self
+            .top_p
+            .map_or_else(|| String::from("-"), |v| v.to_string());
         let wrap = self
             .wrap
             .clone()
             .map_or_else(|| String::from("no"), |v| v.to_string());
-        let items = vec![
+        let mut items = vec![
             ("config_file", path_info(&Self::config_file()?)),
             ("roles_file", path_info(&Self::roles_file()?)),
+            ("macros_file", path_info(&Self::macros_file()?)),
+            ("templates_file", path_info(&Self::templates_file()?)),
             ("messages_file", path_info(&Self::messages_file()?)),
             ("sessions_dir", path_info(&Self::sessions_dir()?)),
+            ("history_file", path_info(&Self::history_file()?)),
+            ("cache_dir", path_info(&Self::cache_dir()?)),
+            (
+                "log_file",
+                self.log_file
+                    .as_ref()
+                    .map_or_else(|| String::from("-"), |v| path_info(v)),
+            ),
+            (
+                "webhook_url",
+                self.webhook_url.clone().unwrap_or_else(|| "-".into()),
+            ),
+            (
+                "webhook_secret",
+                self.webhook_secret
+                    .as_deref()
+                    .map_or_else(|| String::from("-"), redact),
+            ),
             ("model", self.model_info.stringify()),
+            (
+                "model_fallbacks",
+                serde_json::to_string(&self.model_fallbacks)?,
+            ),
+            (
+                "api_base",
+                current_api_base(self).unwrap_or_else(|| "-".into()),
+            ),
+            (
+                "api_key",
+                current_api_key(self).unwrap_or_else(|| "-".into()),
+            ),
+            ("proxy", current_proxy(self).unwrap_or_else(|| "-".into())),
             ("temperature", temperature),
+            ("top_p", top_p),
+            (
+                "max_tokens",
+                self.max_tokens
+                    .map_or_else(|| String::from("-"), |v| v.to_string()),
+            ),
+            (
+                "seed",
+                self.seed
+                    .map_or_else(|| String::from("-"), |v| v.to_string()),
+            ),
+            ("stop", serde_json::to_string(&self.get_stop())?),
+            (
+                "default_role",
+                self.default_role.clone().unwrap_or_else(|| "-".into()),
+            ),
+            (
+                "prompt_prefix",
+                self.prompt_prefix.clone().unwrap_or_else(|| "-".into()),
+            ),
+            (
+                "prompt_suffix",
+                self.prompt_suffix.clone().unwrap_or_else(|| "-".into()),
+            ),
             ("save", self.save.to_string()),
             ("highlight", self.highlight.to_string()),
+            ("no_color", self.color_disabled().to_string()),
             ("light_theme", self.light_theme.to_string()),
+            ("theme", self.theme.clone().unwrap_or_else(|| "-".into())),
             ("wrap", wrap),
             ("wrap_code", self.wrap_code.to_string()),
+            ("render_math", self.render_math.to_string()),
+            ("hyperlinks", self.hyperlinks.to_string()),
+            ("squeeze_blank", self.squeeze_blank.to_string()),
             ("dry_run", self.dry_run.to_string()),
+            ("use_pager", self.use_pager.to_string()),
+            ("stream", self.stream.to_string()),
+            (
+                "stream_flush_interval",
+                self.stream_flush_interval.to_string(),
+            ),
+            ("live_render", self.live_render.to_string()),
+            ("serve_addr", self.serve_addr.clone()),
+            ("show_stream_stats", self.show_stream_stats.to_string()),
+            ("show_reading_time", self.show_reading_time.to_string()),
+            (
+                "tts_command",
+                self.tts_command.clone().unwrap_or_else(|| "-".into()),
+            ),
+            (
+                "reply_filter",
+                self.reply_filter.clone().unwrap_or_else(|| "-".into()),
+            ),
+            ("max_retries", self.max_retries.to_string()),
+            ("retry_delay", self.retry_delay.to_string()),
+            ("request_timeout", self.request_timeout.to_string()),
+            ("cache_ttl", self.cache_ttl.to_string()),
+            ("confirm_overflow", self.confirm_overflow.to_string()),
+            ("confirm_over_tokens", self.confirm_over_tokens.to_string()),
+            ("auto_name_session", self.auto_name_session.to_string()),
             ("keybindings", self.keybindings.stringify().into()),
+            ("max_history", self.max_history.to_string()),
+            (
+                "prompt_format",
+                self.prompt_format.clone().unwrap_or_else(|| "-".into()),
+            ),
         ];
+        if let Some(session) = &self.session {
+            let (input_tokens, output_tokens) = session.usage();
+            let messages = session
+                .messages
+                .iter()
+                .filter(|v| !matches!(v.role, MessageRole::System))
+                .count();
+            items.extend([
+                ("session", session.name.clone()),
+                ("session_messages", messages.to_string()),
+                ("session_input_tokens", input_tokens.to_string()),
+                ("session_output_tokens", output_tokens.to_string()),
+            ]);
+        }
+        let width = items
+            .iter()
+            .map(|(name, _)| name.len() + 1)
+            .max()
+            .unwrap_or_default();
+        let width = width.max(20);
         let mut output = String::new();
         for (name, value) in items {
-            output.push_str(&format!("{name:<20}{value}\n"));
+            output.push_str(&format!("{name:<width$}{value}\n"));
         }
         Ok(output)
     }
 
-    pub fn repl_completions(&self) -> Vec<String> {
-        let mut completion: Vec<String> = self
-            .roles
-            .iter()
-            .map(|v| format!(".role {}", v.name))
-            .collect();
-
-        completion.extend(SET_COMPLETIONS.map(std::string::ToString::to_string));
-        completion.extend(
-            list_models(self)
-                .iter()
-                .map(|v| format!(".model {}", v.stringify())),
-        );
-        completion.extend(
-            list_models(self)
+    /// The arguments that the REPL completes after a command
+    pub fn repl_completions(&self, command: &str) -> Vec<String> {
+        match command {
+            ".role" => self.roles.iter().map(|v| v.name.clone()).collect(),
+            ".model" => {
+                let mut names: Vec<String> = vec!["next".into(), "prev".into()];
+                names.extend(list_models(self).iter().map(|v| v.stringify()));
+                names
+            }
+            ".session" => {
+                let sessions = self.list_sessions().unwrap_or_default();
+                let mut names = vec!["unlock".into()];
+                names.extend(sessions.iter().map(|v| format!("merge {v}")));
+                names.extend(sessions);
+                names
+            }
+            ".replay" => self.list_sessions().unwrap_or_default(),
+            ".macro" => self.macros.keys().map(|v| format!("run {v}")).collect(),
+            ".template" => self.templates.keys().map(|v| format!("use {v}")).collect(),
+            ".set" => SET_COMPLETIONS
                 .iter()
-                .map(|v| format!(".model {}", v.stringify())),
-        );
-        let sessions = self.list_sessions().unwrap_or_default();
-        completion.extend(sessions.iter().map(|v| format!(".session {}", v)));
-        completion
+                .filter_map(|v| v.strip_prefix(".set "))
+                .map(|v| v.to_string())
+                .collect(),
+            _ => vec![],
+        }
     }
 
     pub fn update(&mut self, data: &str) -> Result<()> {
         let parts: Vec<&str> = data.split_whitespace().collect();
+        if let Some(key) = parts.first() {
+            let keys = Self::set_keys();
+            if !keys.contains(key) {
+                let matches = closest_matches(key, &keys);
+                let available = if matches.is_empty() { keys } else { matches };
+                bail!("Unknown key `{key}`. Available: {}", available.join(", "));
+            }
+        }
         if parts.len() != 2 {
             bail!("Usage: .set <key> <value>. If value is null, unset key.");
         }
@@ -422,6 +1540,37 @@ impl Config {
                 };
                 self.set_temperature(value)?;
             }
+            "top_p" => {
+                let value = if unset {
+                    None
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    Some(value)
+                };
+                self.set_top_p(value)?;
+            }
+            "max_tokens" => {
+                let value = if unset {
+                    None
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    Some(value)
+                };
+                if value == Some(0) {
+                    bail!("Invalid value, max_tokens must be greater than 0");
+                }
+                self.max_tokens_override = value;
+                self.apply_max_tokens()?;
+            }
+            "seed" => {
+                let value = if unset {
+                    None
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    Some(value)
+                };
+                self.seed = value;
+            }
             "save" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.save = value;
@@ -430,15 +1579,125 @@ impl Config {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.highlight = value;
             }
+            "no_color" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.no_color = value;
+            }
             "dry_run" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.dry_run = value;
             }
+            "prompt_format" => {
+                self.prompt_format = if unset { None } else { Some(value.to_string()) };
+            }
+            "theme" => {
+                if unset {
+                    self.theme = None;
+                } else {
+                    if Self::load_theme(value)?.is_none() {
+                        bail!("Unknown theme `{value}`");
+                    }
+                    self.theme = Some(value.to_string());
+                }
+            }
+            "render_math" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.render_math = value;
+            }
+            "hyperlinks" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.hyperlinks = value;
+            }
+            "squeeze_blank" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.squeeze_blank = value;
+            }
+            "use_pager" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.use_pager = value;
+            }
+            "stream" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.stream = value;
+            }
+            "auto_name_session" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.auto_name_session = value;
+            }
+            "stream_flush_interval" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.stream_flush_interval = value;
+            }
+            "show_stream_stats" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.show_stream_stats = value;
+            }
+            "show_reading_time" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.show_reading_time = value;
+            }
+            "max_retries" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.max_retries = value;
+            }
+            "request_timeout" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.request_timeout = value;
+            }
+            "cache_ttl" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.cache_ttl = value;
+            }
+            "confirm_over_tokens" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.confirm_over_tokens = value;
+            }
             _ => bail!("Unknown key `{key}`"),
         }
         Ok(())
     }
 
+    /// The keys that `.set` accepts, in the order of its completions
+    fn set_keys() -> Vec<&'static str> {
+        let mut keys: Vec<&str> = SET_COMPLETIONS
+            .iter()
+            .filter_map(|v| v.strip_prefix(".set ")?.split(' ').next())
+            .collect();
+        keys.dedup();
+        keys
+    }
+
+    /// Continue from the messages in a session that is never saved, as the server does
+    pub fn set_context(&mut self, mut messages: Vec<Message>) {
+        if messages.is_empty() {
+            self.session = None;
+            return;
+        }
+        // A session only applies its role to the first input, so the role prompt is put first
+        if let Some(role) = self.role.as_ref().filter(|v| !v.embedded()) {
+            if !messages
+                .iter()
+                .any(|v| matches!(v.role, MessageRole::System))
+            {
+                messages.insert(
+                    0,
+                    Message {
+                        role: MessageRole::System,
+                        content: role.prompt.clone(),
+                    },
+                );
+            }
+        }
+        let mut session = Session::new(
+            TEMP_SESSION_NAME,
+            &self.model_info.stringify(),
+            self.role.clone(),
+        );
+        session.messages = messages;
+        session.update_tokens();
+        self.session = Some(session);
+    }
+
     pub fn start_session(&mut self, session: &Option<String>) -> Result<()> {
         if self.session.is_some() {
             bail!("Already in a session, please use '.clear session' to exit the session first?");
@@ -457,6 +1716,11 @@ impl Config {
                 ));
             }
             Some(name) => {
+                if name != TEMP_SESSION_NAME {
+                    let path = Self::local_path(LAST_SESSION_FILE_NAME)?;
+                    write(&path, name)
+                        .with_context(|| format!("Failed to write to {}", path.display()))?;
+                }
                 let session_path = Self::session_file(name)?;
                 if !session_path.exists() {
                     self.session = Some(Session::new(
@@ -468,6 +1732,7 @@ impl Config {
                     let session = Session::load(name, &session_path)?;
                     let model = session.model.clone();
                     self.temperature = session.temperature;
+                    self.top_p = session.top_p;
                     self.session = Some(session);
                     self.set_model(&model)?;
                 }
@@ -490,10 +1755,151 @@ impl Config {
         Ok(())
     }
 
+    /// Load a session without making it the current one
+    pub fn read_session(&self, name: &str) -> Result<Session> {
+        match self.session.as_ref() {
+            Some(session) if session.name == name => Ok(session.clone()),
+            _ => {
+                let session_path = Self::session_file(name)?;
+                if !session_path.exists() {
+                    bail!("Not found session '{name}'");
+                }
+                Session::load(name, &session_path)
+            }
+        }
+    }
+
+    /// Write a saved session, or the current one, to `path` as JSON
+    pub fn export_session(&self, name: &str, path: &Path) -> Result<()> {
+        let content = self.read_session(name)?.export_json()?;
+        ensure_parent_exists(path)?;
+        write(path, content)
+            .with_context(|| format!("Failed to write session to {}", path.display()))
+    }
+
+    /// Save an exported session under its own name or `name`, returning the name
+    pub fn import_session(&self, path: &Path, name: Option<&str>) -> Result<String> {
+        let content =
+            read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut session = Session::import_json(&content)?;
+        if let Some(name) = name {
+            session.name = name.to_string();
+        }
+        guard_session_name(&session.name)?;
+        let session_path = Self::session_file(&session.name)?;
+        if session_path.exists() {
+            bail!("Session '{}' already exists", session.name);
+        }
+        self.ensure_sessions_dir()?;
+        session.save(&session_path)?;
+        Ok(session.name)
+    }
+
+    /// Rename the session with a slug of the input, a number is appended if the name is taken
+    fn name_session_after(&mut self, input: &str) {
+        let slug = session_slug(input);
+        if slug.is_empty() {
+            return;
+        }
+        let name = (1..10)
+            .map(|i| match i {
+                1 => slug.clone(),
+                _ => format!("{slug}-{i}"),
+            })
+            .find(|v| Self::session_file(v).is_ok_and(|path| !path.exists()));
+        if let Some(name) = name {
+            if let Err(err) = self.rename_session(&name) {
+                eprintln!("{}", format!("Warning: {err:#}").yellow());
+            }
+        }
+    }
+
+    /// Rename the current session, moving its file if it was saved already
+    pub fn rename_session(&mut self, name: &str) -> Result<()> {
+        guard_session_name(name)?;
+        let session = match self.session.as_mut() {
+            Some(v) => v,
+            None => bail!("No session"),
+        };
+        if session.name == name {
+            return Ok(());
+        }
+        let session_path = Self::session_file(name)?;
+        if session_path.exists() {
+            bail!("Session '{name}' already exists");
+        }
+        let old_path = Self::session_file(&session.name)?;
+        if !session.is_temp() && old_path.exists() {
+            rename(&old_path, &session_path).with_context(|| {
+                format!(
+                    "Failed to move {} to {}",
+                    old_path.display(),
+                    session_path.display()
+                )
+            })?;
+            session.path = Some(session_path.display().to_string());
+        }
+        session.name = name.to_string();
+        // The name is saved in the file too, so it is written again on exit
+        session.dirty = true;
+        let path = Self::local_path(LAST_SESSION_FILE_NAME)?;
+        write(&path, name).with_context(|| format!("Failed to write to {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Continue in a copy of the current session saved as `name`, the original is kept
+    pub fn fork_session(&mut self, name: &str) -> Result<()> {
+        guard_session_name(name)?;
+        let session = match self.session.as_mut() {
+            Some(v) => v,
+            None => bail!("No session"),
+        };
+        let session_path = Self::session_file(name)?;
+        if session.name == name || session_path.exists() {
+            bail!("Session '{name}' already exists");
+        }
+        // Unsaved messages of the original would be left only in the fork otherwise
+        if !session.is_temp() {
+            session.save(&Self::session_file(&session.name)?)?;
+        }
+        let mut fork = session.clone();
+        fork.name = name.to_string();
+        fork.path = Some(session_path.display().to_string());
+        fork.dirty = true;
+        fork.save(&session_path)?;
+        self.session = Some(fork);
+        let path = Self::local_path(LAST_SESSION_FILE_NAME)?;
+        write(&path, name).with_context(|| format!("Failed to write to {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Append the messages of a saved session to the current one, returning what could not be kept
+    pub fn merge_session(&mut self, name: &str) -> Result<Vec<String>> {
+        let other = match self.session.as_ref() {
+            Some(session) if session.name == name => bail!("Cannot merge a session into itself"),
+            Some(_) => self.read_session(name)?,
+            None => bail!("No session"),
+        };
+        if other.is_empty() {
+            bail!("Session '{name}' has no messages");
+        }
+        let session = match self.session.as_mut() {
+            Some(v) => v,
+            None => bail!("No session"),
+        };
+        let conflicts = session.merge(&other);
+        if !session.is_temp() {
+            session.save(&Self::session_file(&session.name)?)?;
+        }
+        Ok(conflicts)
+    }
+
     pub fn end_session(&mut self) -> Result<()> {
         if let Some(mut session) = self.session.take() {
             self.last_message = None;
+            self.previous_reply = None;
             self.temperature = self.default_temperature;
+            self.top_p = self.default_top_p;
             if session.should_save() {
                 let ans = Confirm::new("Save session?").with_default(true).prompt()?;
                 if !ans {
@@ -528,8 +1934,83 @@ impl Config {
         }
     }
 
+    /// The saved sessions with their message counts, the last modified first
+    pub fn sessions_info(&self) -> Result<String> {
+        let mut sessions = vec![];
+        for name in self.list_sessions()? {
+            let path = Self::session_file(&name)?;
+            let modified: Option<DateTime<Local>> = path
+                .metadata()
+                .and_then(|v| v.modified())
+                .ok()
+                .map(Into::into);
+            // A broken file is still listed, so that it can be found and fixed
+            let messages = Session::load(&name, &path)
+                .map(|v| {
+                    v.messages
+                        .iter()
+                        .filter(|v| !matches!(v.role, MessageRole::System))
+                        .count()
+                        .to_string()
+                })
+                .unwrap_or_else(|_| "-".into());
+            sessions.push((name, messages, modified));
+        }
+        if sessions.is_empty() {
+            bail!("No saved sessions");
+        }
+        sessions.sort_by_key(|v| std::cmp::Reverse(v.2));
+        let output = sessions
+            .into_iter()
+            .map(|(name, messages, modified)| {
+                let modified = modified
+                    .map(|v| v.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_else(|| "-".into());
+                format!("{name:<24}{messages:>5} messages   {modified}")
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        Ok(output)
+    }
+
+    /// Load a theme by name, `None` if there is no such theme
+    fn load_theme(name: &str) -> Result<Option<Theme>> {
+        let theme = match name {
+            "monokai-extended" => {
+                bincode::deserialize_from(DARK_THEME).expect("Invalid builtin dark theme")
+            }
+            "monokai-extended-light" => {
+                bincode::deserialize_from(LIGHT_THEME).expect("Invalid builtin light theme")
+            }
+            _ => {
+                let theme_path = Self::themes_dir()?.join(format!("{name}.tmTheme"));
+                if !theme_path.exists() {
+                    return Ok(None);
+                }
+                ThemeSet::get_theme(&theme_path)
+                    .with_context(|| format!("Invalid theme at {}", theme_path.display()))?
+            }
+        };
+        Ok(Some(theme))
+    }
+
+    /// Whether the output must be free of colors
+    pub fn color_disabled(&self) -> bool {
+        self.no_color
+            || env::var("NO_COLOR").is_ok_and(|v| !v.is_empty())
+            || !stdout().is_terminal()
+    }
+
     pub fn get_render_options(&self) -> Result<RenderOptions> {
-        let theme = if self.highlight {
+        // Without a theme the markdown is kept as is, with no escape codes
+        let highlight = self.highlight && !self.color_disabled();
+        let named_theme = match self.theme.as_ref() {
+            Some(name) if highlight => Self::load_theme(name)?,
+            _ => None,
+        };
+        let theme = if named_theme.is_some() {
+            named_theme
+        } else if highlight {
             let theme_mode = if self.light_theme { "light" } else { "dark" };
             let theme_filename = format!("{theme_mode}.tmTheme");
             let theme_path = Self::local_path(&theme_filename)?;
@@ -553,7 +2034,14 @@ impl Config {
         } else {
             None
         };
-        Ok(RenderOptions::new(theme, wrap, self.wrap_code))
+        Ok(RenderOptions::new(
+            theme,
+            wrap,
+            self.wrap_code,
+            self.render_math,
+            self.hyperlinks,
+            self.squeeze_blank,
+        ))
     }
 
     pub fn prepare_send_data(&self, content: &str, stream: bool) -> Result<SendData> {
@@ -561,6 +2049,11 @@ impl Config {
         Ok(SendData {
             messages,
             temperature: self.get_temperature(),
+            top_p: self.get_top_p(),
+            max_tokens: self.max_tokens,
+            seed: self.seed,
+            stop: self.get_stop(),
+            images: self.images.clone(),
             stream,
         })
     }
@@ -588,6 +2081,14 @@ impl Config {
         let content = read_to_string(config_path)
             .with_context(|| format!("Failed to load config at {}", config_path.display()))?;
 
+        if content.contains("${") {
+            let mut value: serde_yaml::Value = serde_yaml::from_str(&content)
+                .with_context(|| format!("Invalid config at {}", config_path.display()))?;
+            expand_config_env_vars(&mut value)?;
+            let config: Self = serde_yaml::from_value(value)
+                .with_context(|| format!("Invalid config at {}", config_path.display()))?;
+            return Ok(config);
+        }
         let config: Self = serde_yaml::from_str(&content)
             .with_context(|| format!("Invalid config at {}", config_path.display()))?;
         Ok(config)
@@ -606,6 +2107,29 @@ impl Config {
         Ok(())
     }
 
+    fn load_macros(&mut self) -> Result<()> {
+        let path = Self::macros_file()?;
+        if !path.exists() {
+            return Ok(());
+        }
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to load macros at {}", path.display()))?;
+        self.macros = serde_yaml::from_str(&content).with_context(|| "Invalid macros config")?;
+        Ok(())
+    }
+
+    fn load_templates(&mut self) -> Result<()> {
+        let path = Self::templates_file()?;
+        if !path.exists() {
+            return Ok(());
+        }
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to load templates at {}", path.display()))?;
+        self.templates =
+            serde_yaml::from_str(&content).with_context(|| "Invalid templates config")?;
+        Ok(())
+    }
+
     fn set_model_info(&mut self) -> Result<()> {
         let model = match &self.model {
             Some(v) => v.clone(),
@@ -618,7 +2142,15 @@ impl Config {
                 models[0].stringify()
             }
         };
-        self.set_model(&model)?;
+        if let Err(err) = self.set_model(&model) {
+            match self.fall_back_model(std::slice::from_ref(&model))? {
+                Some(next) => {
+                    let warning = format!("Warning: Unknown model '{model}', using {next}");
+                    eprintln!("{}", warning.yellow());
+                }
+                None => return Err(err),
+            }
+        }
         Ok(())
     }
 
@@ -695,6 +2227,14 @@ impl Config {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelPricing {
+    /// Price per 1k input tokens
+    pub input: f64,
+    /// Price per 1k output tokens
+    pub output: f64,
+}
+
 #[derive(Debug, Clone, Deserialize, Default)]
 pub enum Keybindings {
     #[serde(rename = "emacs")]
@@ -716,6 +2256,79 @@ impl Keybindings {
     }
 }
 
+/// Expand `${NAME}` in the values of `api_key`, `api_keys`, `api_base` and `proxy`, wherever they are
+fn expand_config_env_vars(value: &mut serde_yaml::Value) -> Result<()> {
+    match value {
+        serde_yaml::Value::Mapping(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.as_str().unwrap_or_default();
+                match value {
+                    serde_yaml::Value::String(text)
+                        if matches!(key, "api_key" | "api_base" | "proxy") =>
+                    {
+                        *text = expand_env_vars(text, |name| env::var(name).ok())
+                            .with_context(|| format!("Invalid `{key}` in config"))?;
+                    }
+                    serde_yaml::Value::Sequence(list) if key == "api_keys" => {
+                        for value in list {
+                            if let serde_yaml::Value::String(text) = value {
+                                *text = expand_env_vars(text, |name| env::var(name).ok())
+                                    .with_context(|| format!("Invalid `{key}` in config"))?;
+                            }
+                        }
+                    }
+                    _ => expand_config_env_vars(value)?,
+                }
+            }
+        }
+        serde_yaml::Value::Sequence(list) => {
+            for value in list {
+                expand_config_env_vars(value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// A session name must make a file in the sessions dir and can't be the temp session
+fn guard_session_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(['/', '\\']) || name == TEMP_SESSION_NAME {
+        bail!("Invalid session name '{name}'");
+    }
+    Ok(())
+}
+
+/// Set a top-level key of the config file in place, keeping the rest of the file as is
+fn update_config_file(key: &str, value: &str) -> Result<()> {
+    let path = Config::config_file()?;
+    let content = if path.exists() {
+        read_to_string(&path).with_context(|| format!("Failed to load {}", path.display()))?
+    } else {
+        String::new()
+    };
+    let prefix = format!("{key}:");
+    let line = format!("{key}: {value}");
+    let mut found = false;
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|v| {
+            if v.starts_with(&prefix) {
+                found = true;
+                line.clone()
+            } else {
+                v.to_string()
+            }
+        })
+        .collect();
+    if !found {
+        lines.push(line);
+    }
+    ensure_parent_exists(&path)?;
+    write_atomic(&path, lines.join("\n") + "\n")
+        .with_context(|| format!("Failed to write to {}", path.display()))
+}
+
 fn create_config_file(config_path: &Path) -> Result<()> {
     let ans = Confirm::new("No config file, create a new one?")
         .with_default(true)
@@ -734,7 +2347,7 @@ fn create_config_file(config_path: &Path) -> Result<()> {
     raw_config.push_str(&format!("model: {client}\n"));
 
     ensure_parent_exists(config_path)?;
-    std::fs::write(config_path, raw_config).with_context(|| "Failed to write to config file")?;
+    write_atomic(config_path, raw_config).with_context(|| "Failed to write to config file")?;
     #[cfg(unix)]
     {
         use std::os::unix::prelude::PermissionsExt;
@@ -747,6 +2360,13 @@ fn create_config_file(config_path: &Path) -> Result<()> {
     Ok(())
 }
 
+fn guard_macro_lines(lines: &[String]) -> Result<()> {
+    if lines.iter().any(|v| v.trim_start().starts_with(".macro")) {
+        bail!("A macro cannot run other macros");
+    }
+    Ok(())
+}
+
 fn ensure_parent_exists(path: &Path) -> Result<()> {
     if path.exists() {
         return Ok(());
@@ -1,9 +1,17 @@
 use super::message::{Message, MessageRole};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 const INPUT_PLACEHOLDER: &str = "__INPUT__";
+const INPUT_VARIABLE: &str = "{{input}}";
+
+lazy_static! {
+    static ref VARIABLE_RE: Regex = Regex::new(r"\{\{(\w+)\}\}").unwrap();
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Role {
@@ -13,9 +21,29 @@ pub struct Role {
     pub prompt: String,
     /// What sampling temperature to use, between 0 and 2
     pub temperature: Option<f64>,
+    /// Nucleus sampling probability mass, between 0 and 1
+    pub top_p: Option<f64>,
+    /// Stop sequences, overriding the global ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    /// Default values of the `{{name}}` placeholders in prompt
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
 }
 
 impl Role {
+    /// A role outside the roles file, with only a prompt
+    pub fn new(name: &str, prompt: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            prompt: prompt.to_string(),
+            temperature: None,
+            top_p: None,
+            stop: None,
+            variables: BTreeMap::new(),
+        }
+    }
+
     pub fn info(&self) -> Result<String> {
         let output = serde_yaml::to_string(&self)
             .with_context(|| format!("Unable to show info about role {}", &self.name))?;
@@ -23,7 +51,38 @@ impl Role {
     }
 
     pub fn embedded(&self) -> bool {
-        self.prompt.contains(INPUT_PLACEHOLDER)
+        self.prompt.contains(INPUT_PLACEHOLDER) || self.prompt.contains(INPUT_VARIABLE)
+    }
+
+    /// Names of the `{{name}}` placeholders that have no value yet
+    pub fn missing_variables(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        for caps in VARIABLE_RE.captures_iter(&self.prompt).flatten() {
+            let name = &caps[1];
+            if name != "input" && !names.iter().any(|v| v == name) {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+
+    /// Fill the placeholders with the given values, the rest fall back to their defaults
+    pub fn set_variables(&mut self, values: &[(String, String)]) -> Result<()> {
+        let names = self.missing_variables();
+        for (name, value) in values {
+            if !names.contains(name) {
+                bail!("Unknown variable `{name}` for role {}", self.name);
+            }
+            self.set_variable(name, value);
+        }
+        for (name, value) in self.variables.clone() {
+            self.set_variable(&name, &value);
+        }
+        Ok(())
+    }
+
+    pub fn set_variable(&mut self, name: &str, value: &str) {
+        self.prompt = self.prompt.replace(&format!("{{{{{name}}}}}"), value);
     }
 
     pub fn complete_prompt_args(&mut self, name: &str) {
@@ -41,14 +100,6 @@ impl Role {
         }
     }
 
-    pub fn echo_messages(&self, content: &str) -> String {
-        if self.embedded() {
-            merge_prompt_content(&self.prompt, content)
-        } else {
-            format!("{}\n{content}", self.prompt)
-        }
-    }
-
     pub fn build_messages(&self, content: &str) -> Vec<Message> {
         if self.embedded() {
             let content = merge_prompt_content(&self.prompt, content);
@@ -72,7 +123,9 @@ impl Role {
 }
 
 fn merge_prompt_content(prompt: &str, content: &str) -> String {
-    prompt.replace(INPUT_PLACEHOLDER, content)
+    prompt
+        .replace(INPUT_PLACEHOLDER, content)
+        .replace(INPUT_VARIABLE, content)
 }
 
 fn complete_prompt_args(prompt: &str, name: &str) -> String {
@@ -98,4 +151,22 @@ mod tests {
             "convert foo to bar"
         );
     }
+
+    #[test]
+    fn test_set_variables() {
+        let mut role = Role::new(
+            "translate",
+            "Translate the following into {{lang}} in a {{tone}} tone: {{input}}",
+        );
+        role.variables = BTreeMap::from([("tone".into(), "formal".into())]);
+        assert_eq!(role.missing_variables(), vec!["lang", "tone"]);
+        role.set_variables(&[("lang".into(), "French".into())])
+            .unwrap();
+        assert!(role.missing_variables().is_empty());
+        assert_eq!(
+            merge_prompt_content(&role.prompt, "hello"),
+            "Translate the following into French in a formal tone: hello"
+        );
+        assert!(role.set_variables(&[("foo".into(), "bar".into())]).is_err());
+    }
 }
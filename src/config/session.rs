@@ -2,13 +2,19 @@ use super::message::{num_tokens_from_messages, Message, MessageRole};
 use super::role::Role;
 
 use crate::render::MarkdownRender;
+use crate::utils::{count_tokens, now, write_atomic};
 
 use anyhow::{bail, Context, Result};
+use fancy_regex::{escape, Regex};
 use serde::{Deserialize, Serialize};
-use std::fs::{self, read_to_string};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::read_to_string;
 use std::path::Path;
 
 pub const TEMP_SESSION_NAME: &str = "temp";
+/// Bump it whenever the layout of exported sessions changes
+pub const SESSION_EXPORT_VERSION: u32 = 1;
+const SUMMARY_PREFIX: &str = "Summary of the earlier conversation: ";
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Session {
@@ -16,7 +22,17 @@ pub struct Session {
     pub model: String,
     pub tokens: usize,
     pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
     pub messages: Vec<Message>,
+    /// Values for the `{{name}}` placeholders in inputs
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+    /// Refuse to switch the model of the session
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub locked: bool,
+    /// Turns kept word for word when the session is compressed
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub pinned: BTreeSet<usize>,
     #[serde(skip)]
     pub dirty: bool,
     #[serde(skip)]
@@ -25,15 +41,35 @@ pub struct Session {
     pub name: String,
 }
 
+/// The portable JSON form of a session
+#[derive(Debug, Deserialize, Serialize)]
+struct SessionExport {
+    version: u32,
+    name: String,
+    exported_at: String,
+    role: Option<String>,
+    model: String,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+    messages: Vec<Message>,
+}
+
 impl Session {
     pub fn new(name: &str, model: &str, role: Option<Role>) -> Self {
         let temperature = role.as_ref().and_then(|v| v.temperature);
+        let top_p = role.as_ref().and_then(|v| v.top_p);
         let mut value = Self {
             path: None,
             model: model.to_string(),
             temperature,
+            top_p,
             tokens: 0,
             messages: vec![],
+            variables: BTreeMap::new(),
+            locked: false,
+            pinned: BTreeSet::new(),
             dirty: false,
             role,
             name: name.to_string(),
@@ -61,21 +97,91 @@ impl Session {
         Ok(output)
     }
 
+    pub fn export_json(&self) -> Result<String> {
+        let data = SessionExport {
+            version: SESSION_EXPORT_VERSION,
+            name: self.name.clone(),
+            exported_at: now(),
+            role: self.role.as_ref().map(|v| v.name.clone()),
+            model: self.model.clone(),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            variables: self.variables.clone(),
+            messages: self.messages.clone(),
+        };
+        serde_json::to_string_pretty(&data)
+            .with_context(|| format!("Failed to serde session {}", self.name))
+    }
+
+    pub fn import_json(content: &str) -> Result<Self> {
+        let value: serde_json::Value =
+            serde_json::from_str(content).with_context(|| "Invalid session export")?;
+        match value.get("version").and_then(|v| v.as_u64()) {
+            Some(version) if version == SESSION_EXPORT_VERSION as u64 => {}
+            Some(version) => bail!(
+                "Unsupported session export version {version}, expect {SESSION_EXPORT_VERSION}"
+            ),
+            None => bail!("Invalid session export, missing version"),
+        }
+        let data: SessionExport =
+            serde_json::from_value(value).with_context(|| "Invalid session export")?;
+        let mut expect_user = true;
+        for message in &data.messages {
+            match message.role {
+                MessageRole::System => continue,
+                MessageRole::User if expect_user => {}
+                MessageRole::Assistant if !expect_user => {}
+                _ => bail!("Invalid session export, user and assistant messages must alternate"),
+            }
+            expect_user = !expect_user;
+        }
+        let mut session = Self {
+            path: None,
+            model: data.model,
+            tokens: 0,
+            temperature: data.temperature,
+            top_p: data.top_p,
+            messages: data.messages,
+            variables: data.variables,
+            locked: false,
+            pinned: BTreeSet::new(),
+            dirty: true,
+            role: None,
+            name: data.name,
+        };
+        session.tokens = num_tokens_from_messages(&session.messages);
+        Ok(session)
+    }
+
     pub fn render(&self, render: &mut MarkdownRender) -> Result<String> {
         let temperature = self
             .temperature
             .map_or_else(|| String::from("-"), |v| v.to_string());
+        let top_p = self
+            .top_p
+            .map_or_else(|| String::from("-"), |v| v.to_string());
         let items = vec![
+            ("name", self.name.clone()),
             ("path", self.path.clone().unwrap_or_else(|| "-".into())),
             ("model", self.model.clone()),
             ("tokens", self.tokens.to_string()),
             ("temperature", temperature),
+            ("top_p", top_p),
+            ("pinned", self.pinned_info()),
         ];
         let mut lines = vec![];
         for (name, value) in items {
             lines.push(format!("{name:<20}{value}"));
         }
         lines.push("".into());
+        lines.push(self.render_messages(render));
+        let output = lines.join("\n");
+        Ok(output)
+    }
+
+    /// The exchanges as they were shown in the REPL, replies rendered as markdown
+    pub fn render_messages(&self, render: &mut MarkdownRender) -> String {
+        let mut lines = vec![];
         for message in &self.messages {
             match message.role {
                 MessageRole::System => {
@@ -90,13 +196,13 @@ impl Session {
                 }
             }
         }
-        let output = lines.join("\n");
-        Ok(output)
+        lines.join("\n")
     }
 
     pub fn update_role(&mut self, role: Option<Role>) -> Result<()> {
         self.guard_empty()?;
         self.temperature = role.as_ref().and_then(|v| v.temperature);
+        self.top_p = role.as_ref().and_then(|v| v.top_p);
         self.role = role;
         self.update_tokens();
         Ok(())
@@ -108,6 +214,13 @@ impl Session {
         Ok(())
     }
 
+    pub fn set_locked(&mut self, locked: bool) {
+        if self.locked != locked {
+            self.locked = locked;
+            self.dirty = true;
+        }
+    }
+
     pub fn save(&mut self, session_path: &Path) -> Result<()> {
         if !self.should_save() {
             return Ok(());
@@ -115,7 +228,7 @@ impl Session {
         self.dirty = false;
         let content = serde_yaml::to_string(&self)
             .with_context(|| format!("Failed to serde session {}", self.name))?;
-        fs::write(session_path, content).with_context(|| {
+        write_atomic(session_path, content).with_context(|| {
             format!(
                 "Failed to write session {} to {}",
                 self.name,
@@ -178,9 +291,254 @@ impl Session {
         Ok(())
     }
 
-    pub fn echo_messages(&self, content: &str) -> String {
-        let messages = self.build_emssages(content);
-        serde_yaml::to_string(&messages).unwrap_or_else(|_| "Unable to echo message".into())
+    /// Remove the last exchange, returning the removed messages
+    pub fn pop_message(&mut self) -> Option<Vec<Message>> {
+        let len = self.messages.len();
+        if len < 2 {
+            return None;
+        }
+        let mut removed = self.messages.split_off(len - 2);
+        if self
+            .messages
+            .iter()
+            .all(|v| matches!(v.role, MessageRole::System))
+        {
+            // Drop the role prompt as well, so it will be rebuilt with the next message
+            removed.splice(0..0, self.messages.drain(..));
+        }
+        if self.messages.is_empty() {
+            self.update_tokens();
+        } else {
+            self.tokens = num_tokens_from_messages(&self.messages);
+        }
+        let turns = self.exchanges();
+        self.pinned.retain(|v| *v <= turns);
+        self.dirty = true;
+        Some(removed)
+    }
+
+    /// Pin or unpin a turn, as numbered by `.search`
+    pub fn pin(&mut self, turn: usize, pinned: bool) -> Result<()> {
+        let turns = self.exchanges();
+        if turn == 0 || turn > turns {
+            bail!("No turn {turn}, the session has {turns}");
+        }
+        let changed = if pinned {
+            self.pinned.insert(turn)
+        } else {
+            self.pinned.remove(&turn)
+        };
+        self.dirty |= changed;
+        Ok(())
+    }
+
+    pub fn pinned_info(&self) -> String {
+        if self.pinned.is_empty() {
+            return "-".into();
+        }
+        let turns: Vec<String> = self.pinned.iter().map(|v| v.to_string()).collect();
+        turns.join(", ")
+    }
+
+    pub fn set_variable(&mut self, name: &str, value: Option<&str>) {
+        match value {
+            Some(value) => self.variables.insert(name.to_string(), value.to_string()),
+            None => self.variables.remove(name),
+        };
+        self.dirty = true;
+    }
+
+    /// Fill the `{{name}}` placeholders in the input, returning it with the undefined names
+    pub fn apply_variables(&self, input: &str) -> (String, Vec<String>) {
+        let mut output = String::new();
+        let mut undefined = vec![];
+        let mut rest = input;
+        while let Some(start) = rest.find("{{") {
+            output.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let name = after
+                .find("}}")
+                .map(|end| &after[..end])
+                .filter(|v| !v.is_empty() && v.chars().all(|c| c.is_alphanumeric() || c == '_'));
+            match name {
+                Some(name) => {
+                    match self.variables.get(name) {
+                        Some(value) => output.push_str(value),
+                        None => {
+                            output.push_str(&format!("{{{{{name}}}}}"));
+                            undefined.push(name.to_string());
+                        }
+                    }
+                    rest = &after[name.len() + 2..];
+                }
+                None => {
+                    output.push_str("{{");
+                    rest = after;
+                }
+            }
+        }
+        output.push_str(rest);
+        (output, undefined)
+    }
+
+    /// Find the messages matching the regex, or the plain text if it is not one, ignoring case.
+    /// Returns the turn of each match with a snippet around it.
+    pub fn search(&self, pattern: &str) -> Vec<(usize, MessageRole, String)> {
+        let re = Regex::new(&format!("(?i){pattern}"))
+            .or_else(|_| Regex::new(&format!("(?i){}", escape(pattern))));
+        let re = match re {
+            Ok(v) => v,
+            Err(_) => return vec![],
+        };
+        let mut output = vec![];
+        let mut turn = 0;
+        for message in &self.messages {
+            match message.role {
+                MessageRole::System => continue,
+                MessageRole::User => turn += 1,
+                MessageRole::Assistant => {}
+            }
+            if let Ok(Some(m)) = re.find(&message.content) {
+                let snippet = snippet(&message.content, m.start(), m.end());
+                output.push((turn, message.role.clone(), snippet));
+            }
+        }
+        output
+    }
+
+    /// How many user inputs the session holds
+    pub fn exchanges(&self) -> usize {
+        self.messages
+            .iter()
+            .filter(|v| matches!(v.role, MessageRole::User))
+            .count()
+    }
+
+    /// Replace all but the last `keep` exchanges with a summary, the role prompt stays on top
+    /// and the summary of an earlier compress is replaced
+    pub fn compress(&mut self, summary: &str, keep: usize) {
+        let is_summary = |v: &Message| {
+            matches!(v.role, MessageRole::System) && v.content.starts_with(SUMMARY_PREFIX)
+        };
+        let prompts = self
+            .messages
+            .iter()
+            .take_while(|v| matches!(v.role, MessageRole::System) && !is_summary(v))
+            .count();
+        let mut rest = self.messages.split_off(prompts);
+        if rest.first().is_some_and(is_summary) {
+            rest.remove(0);
+        }
+        let recent = rest.split_off(rest.len() - (keep * 2).min(rest.len()));
+        self.messages.push(Message {
+            role: MessageRole::System,
+            content: format!("{SUMMARY_PREFIX}{summary}"),
+        });
+        // Pinned turns outlive the summary, followed by the recent ones as before
+        let older = rest.len() / 2;
+        let kept: Vec<Message> = rest
+            .chunks(2)
+            .enumerate()
+            .filter(|(i, _)| self.pinned.contains(&(i + 1)))
+            .flat_map(|(_, v)| v.to_vec())
+            .collect();
+        let kept_turns = kept.len() / 2;
+        self.pinned = (1..=kept_turns)
+            .chain(
+                self.pinned
+                    .iter()
+                    .filter(|v| **v > older)
+                    .map(|v| v - older + kept_turns),
+            )
+            .collect();
+        self.messages.extend(kept);
+        self.messages.extend(recent);
+        self.tokens = num_tokens_from_messages(&self.messages);
+        self.dirty = true;
+    }
+
+    /// Append the messages of another session after these ones, returning what could not be kept
+    pub fn merge(&mut self, other: &Session) -> Vec<String> {
+        let is_prompt = |v: &&Message| matches!(v.role, MessageRole::System);
+        let prompts: Vec<&str> = other
+            .messages
+            .iter()
+            .take_while(is_prompt)
+            .map(|v| v.content.as_str())
+            .collect();
+        let mut conflicts = vec![];
+        if other.model != self.model {
+            conflicts.push(format!(
+                "Session '{}' uses {}, keeping {}",
+                other.name, other.model, self.model
+            ));
+        }
+        let keep_prompts = self.messages.is_empty() && self.role.is_none();
+        if self.messages.is_empty() {
+            if let Some(role) = self.role.as_ref().filter(|v| !v.embedded()) {
+                self.messages.push(Message {
+                    role: MessageRole::System,
+                    content: role.prompt.clone(),
+                });
+            }
+        }
+        let own_prompts: Vec<&str> = self
+            .messages
+            .iter()
+            .take_while(is_prompt)
+            .map(|v| v.content.as_str())
+            .collect();
+        if !keep_prompts && !prompts.is_empty() && prompts != own_prompts {
+            conflicts.push(format!(
+                "The role prompt of session '{}' is left out, keeping the current one",
+                other.name
+            ));
+        }
+        let turns = self.exchanges();
+        self.pinned.extend(other.pinned.iter().map(|v| v + turns));
+        let skip = if keep_prompts { 0 } else { prompts.len() };
+        self.messages.extend(other.messages[skip..].iter().cloned());
+        self.tokens = num_tokens_from_messages(&self.messages);
+        self.dirty = true;
+        conflicts
+    }
+
+    pub fn clear_messages(&mut self) {
+        self.messages.clear();
+        self.pinned.clear();
+        self.update_tokens();
+        self.dirty = true;
+    }
+
+    /// Tokens sent and received over all exchanges, since every request resends the context
+    pub fn usage(&self) -> (usize, usize) {
+        let mut input_tokens = 0;
+        let mut output_tokens = 0;
+        for (i, message) in self.messages.iter().enumerate() {
+            if let MessageRole::Assistant = message.role {
+                input_tokens += num_tokens_from_messages(&self.messages[..i]);
+                output_tokens += count_tokens(&message.content);
+            }
+        }
+        (input_tokens, output_tokens)
+    }
+
+    /// The input and reply of the last exchange
+    pub fn last_exchange(&self) -> Option<(String, String)> {
+        match self.messages.as_slice() {
+            [.., input, output]
+                if matches!(input.role, MessageRole::User)
+                    && matches!(output.role, MessageRole::Assistant) =>
+            {
+                Some((input.content.clone(), output.content.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn restore_messages(&mut self, messages: Vec<Message>) {
+        self.messages.extend(messages);
+        self.tokens = num_tokens_from_messages(&self.messages);
     }
 
     pub fn build_emssages(&self, content: &str) -> Vec<Message> {
@@ -201,3 +559,207 @@ impl Session {
         messages
     }
 }
+
+/// A short name for a session from its first input, such as `tokio-task-scheduling`
+pub fn session_slug(text: &str) -> String {
+    const MAX_WORDS: usize = 5;
+    const MAX_LEN: usize = 40;
+    const STOP_WORDS: [&str; 24] = [
+        "a", "an", "and", "are", "can", "do", "does", "for", "how", "i", "in", "is", "me", "my",
+        "of", "on", "please", "the", "to", "what", "why", "with", "you", "your",
+    ];
+    let text = text.to_lowercase();
+    let words: Vec<&str> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|v| !v.is_empty())
+        .collect();
+    let keywords: Vec<&str> = words
+        .iter()
+        .filter(|v| !STOP_WORDS.contains(v))
+        .copied()
+        .collect();
+    // A prompt made only of stop words keeps them
+    let words = if keywords.is_empty() { words } else { keywords };
+    let mut slug = String::new();
+    for word in words.into_iter().take(MAX_WORDS) {
+        if !slug.is_empty() && slug.chars().count() + word.chars().count() >= MAX_LEN {
+            break;
+        }
+        if !slug.is_empty() {
+            slug.push('-');
+        }
+        slug.push_str(word);
+    }
+    slug.chars().take(MAX_LEN).collect()
+}
+
+/// The matched text with some context around it, on a single line
+fn snippet(text: &str, start: usize, end: usize) -> String {
+    const CONTEXT: usize = 30;
+    let before: Vec<char> = text[..start].chars().rev().take(CONTEXT + 1).collect();
+    let after: Vec<char> = text[end..].chars().take(CONTEXT * 2 + 1).collect();
+    let mut output = String::new();
+    if before.len() > CONTEXT {
+        output.push('…');
+    }
+    output.extend(before.iter().take(CONTEXT).rev());
+    output.push_str(&text[start..end]);
+    output.extend(after.iter().take(CONTEXT * 2));
+    if after.len() > CONTEXT * 2 {
+        output.push('…');
+    }
+    output.replace(['\n', '\r'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(session: &Session) -> Vec<&str> {
+        session
+            .messages
+            .iter()
+            .map(|v| v.content.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_variables() {
+        let mut session = Session::new("test", "openai:gpt-3.5-turbo", None);
+        session.set_variable("project", Some("aichat"));
+        assert_eq!(
+            session.apply_variables("build {{project}} with {{tool}}, {{ x }}"),
+            (
+                "build aichat with {{tool}}, {{ x }}".to_string(),
+                vec!["tool".to_string()]
+            )
+        );
+        assert_eq!(
+            session.apply_variables("no vars {{"),
+            ("no vars {{".to_string(), vec![])
+        );
+    }
+
+    #[test]
+    fn test_session_slug() {
+        assert_eq!(
+            session_slug("How does tokio schedule tasks?"),
+            "tokio-schedule-tasks"
+        );
+        assert_eq!(
+            session_slug("Write a parser for the TOML config format in Rust, please"),
+            "write-parser-toml-config-format"
+        );
+        assert_eq!(session_slug("how are you"), "how-are-you");
+        assert_eq!(session_slug("???"), "");
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut session = Session::new("a", "openai:gpt-4", None);
+        session.add_message("one", "1").unwrap();
+        let mut other = Session::new("b", "openai:gpt-3.5-turbo", None);
+        other.messages.push(Message {
+            role: MessageRole::System,
+            content: "be brief".into(),
+        });
+        other.add_message("two", "2").unwrap();
+        let conflicts = session.merge(&other);
+        assert_eq!(conflicts.len(), 2);
+        let contents = contents(&session);
+        assert_eq!(contents, vec!["one", "1", "two", "2"]);
+        assert_eq!(session.model, "openai:gpt-4");
+
+        let mut empty = Session::new("c", "openai:gpt-3.5-turbo", None);
+        assert!(empty.merge(&other).is_empty());
+        assert_eq!(empty.messages.len(), 3);
+    }
+
+    #[test]
+    fn test_compress_pinned() {
+        let mut session = Session::new("test", "openai:gpt-3.5-turbo", None);
+        for i in 1..=5 {
+            session
+                .add_message(&format!("q{i}"), &format!("a{i}"))
+                .unwrap();
+        }
+        session.pin(2, true).unwrap();
+        session.pin(5, true).unwrap();
+        assert!(session.pin(6, true).is_err());
+        session.compress("earlier", 2);
+        let contents = contents(&session);
+        assert_eq!(
+            contents,
+            vec![
+                "Summary of the earlier conversation: earlier",
+                "q2",
+                "a2",
+                "q4",
+                "a4",
+                "q5",
+                "a5"
+            ]
+        );
+        assert_eq!(session.pinned_info(), "1, 3");
+        session.pop_message();
+        assert_eq!(session.pinned_info(), "1");
+    }
+
+    #[test]
+    fn test_compress_again() {
+        let mut session = Session::new("test", "openai:gpt-3.5-turbo", None);
+        session.messages.push(Message {
+            role: MessageRole::System,
+            content: "You are a poet".into(),
+        });
+        for i in 1..=3 {
+            session
+                .add_message(&format!("q{i}"), &format!("a{i}"))
+                .unwrap();
+        }
+        session.compress("first", 1);
+        session.add_message("q4", "a4").unwrap();
+        session.compress("second", 1);
+        let contents = contents(&session);
+        assert_eq!(
+            contents,
+            vec![
+                "You are a poet",
+                "Summary of the earlier conversation: second",
+                "q4",
+                "a4"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let mut session = Session::new("test", "openai:gpt-3.5-turbo", None);
+        session.add_message("What is Rust?", "A language").unwrap();
+        session
+            .add_message("and tokio (async)?", "A runtime for rust")
+            .unwrap();
+        let turns: Vec<usize> = session.search("rust").iter().map(|v| v.0).collect();
+        assert_eq!(turns, vec![1, 2]);
+        assert_eq!(session.search("(async")[0].2, "and tokio (async)?");
+        assert!(session.search("python").is_empty());
+    }
+
+    #[test]
+    fn test_export_json() {
+        let mut session = Session::new("test", "openai:gpt-3.5-turbo", None);
+        session.add_message("hi", "hello").unwrap();
+        session.set_variable("lang", Some("rust"));
+        let imported = Session::import_json(&session.export_json().unwrap()).unwrap();
+        assert_eq!(imported.name, "test");
+        assert_eq!(imported.messages.len(), 2);
+        assert_eq!(imported.variables, session.variables);
+
+        let newer = r#"{"version": 2, "name": "test", "model": "x", "messages": []}"#;
+        assert!(Session::import_json(newer).is_err());
+        let broken = r#"{"version": 1, "name": "test", "exported_at": "", "role": null,
+            "model": "x", "temperature": null, "top_p": null,
+            "messages": [{"role": "assistant", "content": "hello"}]}"#;
+        assert!(Session::import_json(broken).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+const INPUT_VARIABLE: &str = "{{input}}";
+
+/// Put the input in place of `{{input}}`, or after the template if it has none
+pub fn fill_template(template: &str, input: &str) -> String {
+    if template.contains(INPUT_VARIABLE) {
+        template.replace(INPUT_VARIABLE, input)
+    } else if input.is_empty() {
+        template.to_string()
+    } else {
+        format!("{template}\n{input}")
+    }
+}
+
+/// Read the text of `.template save`, a double-quoted text may use `\n`, `\t`, `\"` and `\\`
+pub fn parse_template_text(text: &str) -> String {
+    let text = text.trim();
+    let quoted = match text.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(v) => v,
+        None => return text.to_string(),
+    };
+    let mut output = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('t') => output.push('\t'),
+            Some(c @ ('"' | '\\')) => output.push(c),
+            Some(c) => {
+                output.push('\\');
+                output.push(c);
+            }
+            None => output.push('\\'),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_template() {
+        assert_eq!(
+            fill_template("Review:\n{{input}}", "fn a()"),
+            "Review:\nfn a()"
+        );
+        assert_eq!(fill_template("Explain this", "x"), "Explain this\nx");
+        assert_eq!(fill_template("Explain this", ""), "Explain this");
+    }
+
+    #[test]
+    fn test_parse_template_text() {
+        assert_eq!(
+            parse_template_text(r#""Review this code for bugs:\n{{input}}""#),
+            "Review this code for bugs:\n{{input}}"
+        );
+        assert_eq!(parse_template_text(r#""say \"hi\" \d""#), r#"say "hi" \d"#);
+        assert_eq!(parse_template_text("plain {{input}}"), "plain {{input}}");
+    }
+}
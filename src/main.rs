@@ -3,30 +3,98 @@ mod client;
 mod config;
 mod render;
 mod repl;
+mod serve;
 #[macro_use]
 mod utils;
 
 use crate::cli::Cli;
-use crate::client::Client;
+use crate::client::{send_with_fallback, wait_webhooks, ApiError};
 use crate::config::{Config, SharedConfig};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use clap::Parser;
-use client::{init_client, list_models};
+use client::list_models;
 use crossbeam::sync::WaitGroup;
 use is_terminal::IsTerminal;
 use parking_lot::RwLock;
 use render::{render_stream, MarkdownRender};
-use repl::{AbortSignal, Repl};
+use repl::{parse_role_args, AbortSignal, Repl};
+use serde_json::json;
 use std::io::{stdin, Read};
 use std::sync::Arc;
 use std::{io::stdout, process::exit};
-use utils::cl100k_base_singleton;
+use utils::{cl100k_base_singleton, count_tokens};
 
-fn main() -> Result<()> {
+/// Exit code of a request rejected or failed by the API
+const EXIT_API_ERROR: i32 = 2;
+/// Exit code of an invalid config, model, role or session
+const EXIT_CONFIG_ERROR: i32 = 3;
+
+fn main() {
     let cli = Cli::parse();
+    let config = match init_config(&cli) {
+        Ok(v) => v,
+        Err(err) => exit_on_error(&err, EXIT_CONFIG_ERROR, cli.json),
+    };
+    let ret = run(&cli, config);
+    wait_webhooks();
+    if let Err(err) = ret {
+        let is_api_error = err
+            .chain()
+            .any(|v| v.is::<ApiError>() || v.is::<reqwest::Error>());
+        let code = if is_api_error { EXIT_API_ERROR } else { 1 };
+        exit_on_error(&err, code, cli.json)
+    }
+}
+
+fn exit_on_error(err: &anyhow::Error, code: i32, json: bool) -> ! {
+    if json {
+        println!("{}", json!({ "error": format!("{err:#}") }));
+    } else {
+        eprintln!("Error: {err:?}");
+    }
+    exit(code)
+}
+
+fn init_config(cli: &Cli) -> Result<SharedConfig> {
+    let config = Arc::new(RwLock::new(Config::init(cli.text().is_none())?));
+    if let Some(wrap) = &cli.wrap {
+        config.write().set_wrap(wrap)?;
+    }
+    if cli.light_theme {
+        config.write().light_theme = true;
+    }
+    if cli.yes {
+        config.write().assume_yes = true;
+    }
+    if cli.dry_run {
+        config.write().dry_run = true;
+    }
+    if let Some(args) = &cli.role {
+        let (name, variables) = parse_role_args(args).ok_or_else(|| {
+            anyhow!("Invalid role '{args}', expected <name> [<variable>=<value>]...")
+        })?;
+        config.write().set_role(name, &variables)?;
+    }
+    if let Some(session) = &cli.session {
+        config.write().start_session(session)?;
+    }
+    // After the session, which would otherwise switch back to its own model
+    if let Some(model) = &cli.model {
+        config.read().guard_model_lock()?;
+        config.write().set_model(model)?;
+    }
+    if cli.no_highlight {
+        config.write().highlight = false;
+    }
+    if cli.no_stream {
+        config.write().stream = false;
+    }
+    Ok(config)
+}
+
+fn run(cli: &Cli, config: SharedConfig) -> Result<()> {
     let text = cli.text();
-    let config = Arc::new(RwLock::new(Config::init(text.is_none())?));
     if cli.list_roles {
         config
             .read()
@@ -36,6 +104,10 @@ fn main() -> Result<()> {
         exit(0);
     }
     if cli.list_models {
+        if cli.json {
+            println!("{}", models_json(&config.read()));
+            exit(0);
+        }
         for model in list_models(&config.read()) {
             println!("{}", model.stringify());
         }
@@ -46,87 +118,144 @@ fn main() -> Result<()> {
         println!("{sessions}");
         exit(0);
     }
-    if let Some(wrap) = &cli.wrap {
-        config.write().set_wrap(wrap)?;
-    }
-    if cli.light_theme {
-        config.write().light_theme = true;
-    }
-    if cli.dry_run {
-        config.write().dry_run = true;
-    }
-    if let Some(model) = &cli.model {
-        config.write().set_model(model)?;
-    }
-    if let Some(name) = &cli.role {
-        config.write().set_role(name)?;
-    }
-    if let Some(session) = &cli.session {
-        config.write().start_session(session)?;
-    }
-    if cli.no_highlight {
-        config.write().highlight = false;
-    }
     if cli.info {
         let info = if let Some(session) = &config.read().session {
             session.export()?
-        } else if let Some(role) = &config.read().role {
-            role.info()?
+        } else if cli.role.is_some() {
+            // The default role alone still shows the config
+            config.read().role_info()?
         } else {
             config.read().info()?
         };
         println!("{info}");
         exit(0);
     }
-    let no_stream = cli.no_stream;
-    let client = init_client(config.clone())?;
+    if let Some(addr) = &cli.serve {
+        let addr = addr
+            .clone()
+            .unwrap_or_else(|| config.read().serve_addr.clone());
+        ctrlc::set_handler(|| exit(0)).expect("Error setting Ctrl-C handler");
+        return serve::run(config, &addr);
+    }
+    let no_stream = !config.read().use_stream();
+    if cli.json {
+        let input = read_input(text)?;
+        return start_json(&config, &input);
+    }
     if stdin().is_terminal() {
         match text {
-            Some(text) => start_directive(client.as_ref(), &config, &text, no_stream),
+            Some(text) => start_directive(&config, &text, no_stream),
             None => start_interactive(config),
         }
     } else {
-        let mut input = String::new();
-        stdin().read_to_string(&mut input)?;
-        if let Some(text) = text {
-            input = format!("{text}\n{input}");
-        }
-        start_directive(client.as_ref(), &config, &input, no_stream)
+        let input = read_input(text)?;
+        start_directive(&config, &input, no_stream)
     }
 }
 
-fn start_directive(
-    client: &dyn Client,
-    config: &SharedConfig,
-    input: &str,
-    no_stream: bool,
-) -> Result<()> {
+/// The available models and the current one, for tools around aichat
+fn models_json(config: &Config) -> serde_json::Value {
+    let current = config.model_info.stringify();
+    let models: Vec<serde_json::Value> = list_models(config)
+        .iter()
+        .map(|v| {
+            let id = v.stringify();
+            let pricing = config
+                .pricing
+                .get(&id)
+                .map(|v| json!({ "input": v.input, "output": v.output }));
+            json!({
+                "id": id,
+                "client": v.client,
+                "name": v.name,
+                "max_tokens": v.max_tokens,
+                "vision": v.vision,
+                "pricing": pricing,
+                "current": id == current,
+            })
+        })
+        .collect();
+    json!({ "current": current, "models": models })
+}
+
+/// Combine the text with the piped input, if any
+fn read_input(text: Option<String>) -> Result<String> {
+    if stdin().is_terminal() {
+        return text.ok_or_else(|| anyhow!("No input"));
+    }
+    let mut input = String::new();
+    stdin().read_to_string(&mut input)?;
+    if let Some(text) = text {
+        input = format!("{text}\n{input}");
+    }
+    if input.trim().is_empty() {
+        bail!("No input");
+    }
+    Ok(input)
+}
+
+/// Send the input without rendering and print the reply as JSON
+fn start_json(config: &SharedConfig, input: &str) -> Result<()> {
+    if let Some(session) = &config.read().session {
+        session.guard_save()?;
+    }
+    config.write().fill_role_variables()?;
+    if !config.read().check_context_window(input)? {
+        bail!("Canceled");
+    }
+    let input_tokens = config.read().input_tokens(input)?;
+    let abort = AbortSignal::new();
+    let output = send_with_fallback(config, |client| client.send_message(input, abort.clone()))?;
+    let output = config.read().filter_reply(&output)?;
+    let output_tokens = count_tokens(&output);
+    let value = json!({
+        "model": config.read().model_info.stringify(),
+        "reply": output,
+        "tokens": {
+            "input": input_tokens,
+            "output": output_tokens,
+            "total": input_tokens + output_tokens,
+        },
+    });
+    println!("{value}");
+    config.write().save_message(input, &output, false)
+}
+
+fn start_directive(config: &SharedConfig, input: &str, no_stream: bool) -> Result<()> {
     if let Some(session) = &config.read().session {
         session.guard_save()?;
     }
     if !stdout().is_terminal() {
         config.write().highlight = false;
     }
+    config.write().fill_role_variables()?;
+    if !config.read().check_context_window(input)? {
+        return Ok(());
+    }
     config.read().maybe_print_send_tokens(input);
+    let abort = AbortSignal::new();
     let output = if no_stream {
         let render_options = config.read().get_render_options()?;
-        let output = client.send_message(input)?;
+        let output =
+            send_with_fallback(config, |client| client.send_message(input, abort.clone()))?;
+        let output = config.read().filter_reply(&output)?;
         let mut markdown_render = MarkdownRender::init(render_options)?;
         println!("{}", markdown_render.render(&output).trim());
         output
     } else {
         let wg = WaitGroup::new();
-        let abort = AbortSignal::new();
         let abort_clone = abort.clone();
         ctrlc::set_handler(move || {
             abort_clone.set_ctrlc();
         })
         .expect("Error setting Ctrl-C handler");
-        let output = render_stream(input, client, config, false, abort, wg.clone())?;
+        let output = send_with_fallback(config, |client| {
+            render_stream(input, client, config, false, abort.clone(), wg.clone())
+        })?;
         wg.wait();
         output
     };
-    config.write().save_message(input, &output)
+    config.write().save_message(input, &output, abort.aborted())
 }
 
 fn start_interactive(config: SharedConfig) -> Result<()> {
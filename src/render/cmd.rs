@@ -1,4 +1,4 @@
-use super::MarkdownRender;
+use super::{spinner::Spinner, MarkdownRender};
 
 use crate::print_now;
 use crate::repl::{ReplyStreamEvent, SharedAbortSignal};
@@ -6,29 +6,41 @@ use crate::utils::{spaces, split_line_sematic, split_line_tail};
 
 use anyhow::Result;
 use crossbeam::channel::Receiver;
+use std::io::stdout;
 use textwrap::core::display_width;
 
 pub fn cmd_render_stream(
     rx: &Receiver<ReplyStreamEvent>,
     render: &mut MarkdownRender,
     abort: &SharedAbortSignal,
+    spinner: bool,
 ) -> Result<()> {
     let mut buffer = String::new();
+    let mut footers = vec![];
     let mut col = 0;
+    let mut spinner = spinner.then(Spinner::new);
     loop {
         if abort.aborted() {
+            if let Some(spinner) = spinner.as_mut() {
+                spinner.clear(&mut stdout())?;
+            }
             return Ok(());
         }
         if let Ok(evt) = rx.try_recv() {
+            if let Some(mut spinner) = spinner.take() {
+                spinner.clear(&mut stdout())?;
+            }
             match evt {
                 ReplyStreamEvent::Text(text) => {
                     if text.contains('\n') {
                         let text = format!("{buffer}{text}");
                         let (head, tail) = split_line_tail(&text);
                         buffer = tail.to_string();
-                        let input = format!("{}{head}", spaces(col));
-                        let output = render.render(&input);
-                        print_now!("{}\n", &output[col..]);
+                        let prefix = spaces(col);
+                        let input = format!("{prefix}{head}");
+                        if let Some(output) = render.render_stream(&input, col > 0) {
+                            print_now!("{}\n", output.strip_prefix(&prefix).unwrap_or(&output));
+                        }
                         col = 0;
                     } else {
                         buffer = format!("{buffer}{text}");
@@ -40,9 +52,10 @@ pub fn cmd_render_stream(
                         {
                             if let Some((head, remain)) = split_line_sematic(&buffer) {
                                 buffer = remain;
-                                let input = format!("{}{head}", spaces(col));
+                                let prefix = spaces(col);
+                                let input = format!("{prefix}{head}");
                                 let output = render.render(&input);
-                                let output = &output[col..];
+                                let output = output.strip_prefix(&prefix).unwrap_or(&output);
                                 let (_, tail) = split_line_tail(output);
                                 if render.wrap_width().is_some() {
                                     if output.contains('\n') {
@@ -56,12 +69,22 @@ pub fn cmd_render_stream(
                         }
                     }
                 }
+                ReplyStreamEvent::Footer(text) => {
+                    footers.push(text);
+                }
                 ReplyStreamEvent::Done => {
-                    let input = format!("{}{buffer}", spaces(col));
-                    print_now!("{}\n", render.render(&input));
+                    let prefix = spaces(col);
+                    let input = format!("{prefix}{buffer}");
+                    let output = render.render(&input);
+                    print_now!("{}\n", output.strip_prefix(&prefix).unwrap_or(&output));
+                    for footer in footers {
+                        print_now!("{footer}\n");
+                    }
                     break;
                 }
             }
+        } else if let Some(spinner) = spinner.as_mut() {
+            spinner.tick(&mut stdout())?;
         }
     }
     Ok(())
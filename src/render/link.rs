@@ -0,0 +1,79 @@
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use textwrap::core::display_width;
+
+lazy_static! {
+    /// `[text](url)`, but not an image or a link inside inline code
+    static ref LINK_RE: Regex = Regex::new(r"(?<![!\\])\[([^\[\]]+)\]\(([^()\s]+)\)").unwrap();
+    static ref OSC8_RE: Regex = Regex::new(r"\x1b\]8;;[^\x1b\x07]*(\x1b\\|\x07)").unwrap();
+}
+
+pub fn has_link(line: &str) -> bool {
+    LINK_RE.is_match(line).unwrap_or_default()
+}
+
+/// Split a line into `(text, url)` segments, the url is only set for the text of a link
+pub fn split_links(line: &str) -> Vec<(String, Option<String>)> {
+    let mut segments = vec![];
+    let mut last = 0;
+    let mut scanned = 0;
+    let mut in_code = false;
+    for caps in LINK_RE.captures_iter(line).flatten() {
+        let whole = caps.get(0).unwrap();
+        in_code ^= line[scanned..whole.start()].matches('`').count() % 2 == 1;
+        scanned = whole.start();
+        if in_code {
+            continue;
+        }
+        if whole.start() > last {
+            segments.push((line[last..whole.start()].to_string(), None));
+        }
+        segments.push((caps[1].to_string(), Some(caps[2].to_string())));
+        last = whole.end();
+    }
+    if last < line.len() {
+        segments.push((line[last..].to_string(), None));
+    }
+    segments
+}
+
+/// Make the text a hyperlink to the url in terminals that support OSC 8
+pub fn osc8_link(text: &str, url: &str) -> String {
+    format!("\x1b]8;;{url}\x1b\\{text}\x1b]8;;\x1b\\")
+}
+
+/// The columns taken by the text, hyperlinks only count for their text
+pub fn visible_width(text: &str) -> usize {
+    if !text.contains("\x1b]8;;") {
+        return display_width(text);
+    }
+    display_width(&OSC8_RE.replace_all(text, ""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_links() {
+        assert_eq!(
+            split_links("see [docs](https://a.io/x) now"),
+            vec![
+                ("see ".into(), None),
+                ("docs".into(), Some("https://a.io/x".into())),
+                (" now".into(), None),
+            ]
+        );
+        assert!(!has_link("an image ![logo](a.png)"));
+        assert_eq!(
+            split_links("`[a](b)` [c](d)"),
+            vec![("`[a](b)` ".into(), None), ("c".into(), Some("d".into()))]
+        );
+    }
+
+    #[test]
+    fn test_visible_width() {
+        assert_eq!(visible_width(&osc8_link("docs", "https://a.io/x")), 4);
+        assert_eq!(visible_width("plain"), 5);
+    }
+}
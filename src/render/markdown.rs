@@ -1,3 +1,7 @@
+use super::link::{has_link, osc8_link, split_links};
+use super::math::{math_to_unicode, split_math};
+use super::table::{format_table, is_table_row};
+
 use anyhow::{anyhow, Context, Result};
 use crossterm::style::{Color, Stylize};
 use crossterm::terminal;
@@ -27,6 +31,10 @@ pub struct MarkdownRender {
     code_syntax: Option<SyntaxReference>,
     prev_line_type: LineType,
     wrap_width: Option<u16>,
+    /// Rows of a streamed table, held until it ends to align the columns
+    table: Vec<String>,
+    /// Whether the last line was blank, to squeeze the next ones across chunks
+    prev_blank: bool,
 }
 
 impl MarkdownRender {
@@ -60,6 +68,8 @@ impl MarkdownRender {
             code_syntax: None,
             prev_line_type: line_type,
             wrap_width,
+            table: vec![],
+            prev_blank: false,
             options,
         })
     }
@@ -76,10 +86,87 @@ impl MarkdownRender {
     }
 
     pub fn render(&mut self, text: &str) -> String {
-        text.split('\n')
-            .map(|line| self.render_line_mut(line))
-            .collect::<Vec<String>>()
-            .join("\n")
+        self.render_lines(Some(text), true, false).join("\n")
+    }
+
+    /// Render complete lines of a stream, `None` if they are all held as table rows.
+    /// `continued` tells that the first line goes on from one already printed, so it is never a table row
+    pub(crate) fn render_stream(&mut self, text: &str, continued: bool) -> Option<String> {
+        let lines = self.render_lines(Some(text), false, continued);
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+
+    /// Render the held table rows along with the unfinished last line of a stream
+    pub(crate) fn finish_table(&mut self, tail: &str) -> Option<String> {
+        if self.table.is_empty() {
+            return None;
+        }
+        let tail = (!tail.is_empty()).then_some(tail);
+        Some(self.render_lines(tail, true, false).join("\n"))
+    }
+
+    fn render_lines(&mut self, text: Option<&str>, finish: bool, continued: bool) -> Vec<String> {
+        let mut lines = std::mem::take(&mut self.table);
+        let continued_at = continued.then_some(lines.len());
+        if let Some(text) = text {
+            lines.extend(text.split('\n').map(|v| v.to_string()));
+        }
+        let is_row = |i: usize, line: &str| Some(i) != continued_at && is_table_row(line);
+        // Aligned tables are for the terminal, plain output keeps the markdown
+        let align_table = self.options.theme.is_some();
+        let mut output = vec![];
+        let mut i = 0;
+        while i < lines.len() {
+            if self.options.squeeze_blank && !self.is_code() {
+                let blank = lines[i].trim().is_empty();
+                if blank && self.prev_blank {
+                    i += 1;
+                    continue;
+                }
+                self.prev_blank = blank;
+            }
+            if align_table && !self.is_code() && is_row(i, &lines[i]) {
+                let end = i + lines[i..]
+                    .iter()
+                    .enumerate()
+                    .take_while(|(j, v)| is_row(i + j, v))
+                    .count();
+                if end == lines.len() && !finish {
+                    self.table = lines.split_off(i);
+                    break;
+                }
+                output.extend(self.render_table(&lines[i..end]));
+                i = end;
+                continue;
+            }
+            let table_row = is_row(i, &lines[i]);
+            output.push(self.render_line_mut(&lines[i], table_row));
+            i += 1;
+        }
+        output
+    }
+
+    fn render_table(&mut self, rows: &[String]) -> Vec<String> {
+        let rows_ref: Vec<&str> = rows.iter().map(|v| v.as_str()).collect();
+        match format_table(&rows_ref) {
+            Some(lines) => {
+                self.prev_line_type = LineType::Normal;
+                lines
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, line)| match i {
+                        0 => line.bold().to_string(),
+                        1 => line,
+                        _ => self.highlight_text(&line, &self.md_syntax),
+                    })
+                    .collect()
+            }
+            None => rows.iter().map(|v| self.render_line_mut(v, true)).collect(),
+        }
     }
 
     pub fn render_line(&self, line: &str) -> String {
@@ -87,16 +174,16 @@ impl MarkdownRender {
         if is_code {
             self.highlight_code_line(line, &code_syntax)
         } else {
-            self.highlight_line(line, &self.md_syntax, false)
+            self.highlight_line(line, &self.md_syntax, false, is_table_row(line))
         }
     }
 
-    fn render_line_mut(&mut self, line: &str) -> String {
+    fn render_line_mut(&mut self, line: &str, table_row: bool) -> String {
         let (line_type, code_syntax, is_code) = self.check_line(line);
         let output = if is_code {
             self.highlight_code_line(line, &code_syntax)
         } else {
-            self.highlight_line(line, &self.md_syntax, false)
+            self.highlight_line(line, &self.md_syntax, false, table_row)
         };
         self.prev_line_type = line_type;
         self.code_syntax = code_syntax;
@@ -145,23 +232,88 @@ impl MarkdownRender {
         (line_type, code_syntax, is_code)
     }
 
-    fn highlight_line(&self, line: &str, syntax: &SyntaxReference, is_code: bool) -> String {
+    fn highlight_line(
+        &self,
+        line: &str,
+        syntax: &SyntaxReference,
+        is_code: bool,
+        table_row: bool,
+    ) -> String {
+        if !is_code && self.options.math && line.contains('$') {
+            return self.highlight_math_line(line, syntax);
+        }
+        // Plain output keeps the markdown of links as is
+        if !is_code && self.options.theme.is_some() && has_link(line) {
+            return self.highlight_link_line(line, syntax);
+        }
+        let highlighted = self.highlight_text(line, syntax);
+        // Wrapping a table row breaks the table apart
+        if !is_code && table_row {
+            return highlighted;
+        }
+        self.wrap_line(highlighted, is_code)
+    }
+
+    fn highlight_text(&self, line: &str, syntax: &SyntaxReference) -> String {
         let ws: String = line.chars().take_while(|c| c.is_whitespace()).collect();
         let trimed_line: &str = &line[ws.len()..];
-        let mut line_highlighted = None;
         if let Some(theme) = &self.options.theme {
             let mut highlighter = HighlightLines::new(syntax, theme);
             if let Ok(ranges) = highlighter.highlight_line(trimed_line, &self.syntax_set) {
-                line_highlighted = Some(format!("{ws}{}", as_terminal_escaped(&ranges)))
+                return format!("{ws}{}", as_terminal_escaped(&ranges));
+            }
+        }
+        line.to_string()
+    }
+
+    fn highlight_math_line(&self, line: &str, syntax: &SyntaxReference) -> String {
+        let mut highlighter = self
+            .options
+            .theme
+            .as_ref()
+            .map(|theme| HighlightLines::new(syntax, theme));
+        let mut output = String::new();
+        for (text, is_math) in split_math(line) {
+            if is_math {
+                let math = math_to_unicode(&text);
+                match highlighter {
+                    Some(_) => output.push_str(&math.italic().to_string()),
+                    None => output.push_str(&math),
+                }
+            } else {
+                let highlighted = highlighter
+                    .as_mut()
+                    .and_then(|v| v.highlight_line(&text, &self.syntax_set).ok())
+                    .map(|ranges| as_terminal_escaped(&ranges));
+                output.push_str(&highlighted.unwrap_or(text));
+            }
+        }
+        self.wrap_line(output, false)
+    }
+
+    fn highlight_link_line(&self, line: &str, syntax: &SyntaxReference) -> String {
+        let mut output = String::new();
+        for (text, url) in split_links(line) {
+            match url {
+                Some(url) if self.options.hyperlinks => {
+                    output.push_str(&osc8_link(&text.underlined().to_string(), &url))
+                }
+                Some(url) => {
+                    output.push_str(&format!("{} ({})", text.underlined(), url.dim()));
+                }
+                None => output.push_str(&self.highlight_text(&text, syntax)),
             }
         }
-        let line = line_highlighted.unwrap_or_else(|| line.into());
-        self.wrap_line(line, is_code)
+        // Wrapping counts the hidden url of a hyperlink and may break it apart
+        if self.options.hyperlinks {
+            return output;
+        }
+        self.wrap_line(output, false)
     }
 
     fn highlight_code_line(&self, line: &str, code_syntax: &Option<SyntaxReference>) -> String {
         if let Some(syntax) = code_syntax {
-            self.highlight_line(line, syntax, true)
+            self.highlight_line(line, syntax, true, false)
         } else {
             let line = match self.code_color {
                 Some(color) => line.with(color).to_string(),
@@ -198,14 +350,29 @@ pub struct RenderOptions {
     pub theme: Option<Theme>,
     pub wrap: Option<String>,
     pub wrap_code: bool,
+    pub math: bool,
+    /// Make links clickable with OSC 8, instead of showing the url after the text
+    pub hyperlinks: bool,
+    /// Collapse runs of blank lines outside code blocks into one
+    pub squeeze_blank: bool,
 }
 
 impl RenderOptions {
-    pub(crate) fn new(theme: Option<Theme>, wrap: Option<String>, wrap_code: bool) -> Self {
+    pub(crate) fn new(
+        theme: Option<Theme>,
+        wrap: Option<String>,
+        wrap_code: bool,
+        math: bool,
+        hyperlinks: bool,
+        squeeze_blank: bool,
+    ) -> Self {
         Self {
             theme,
             wrap,
             wrap_code,
+            math,
+            hyperlinks,
+            squeeze_blank,
         }
     }
 }
@@ -270,6 +437,51 @@ fn detect_code_block(line: &str) -> Option<String> {
     Some(lang)
 }
 
+/// Extract the contents of fenced code blocks, without the fence lines
+pub fn extract_code_blocks(text: &str) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut current: Option<Vec<&str>> = None;
+    for line in text.lines() {
+        if detect_code_block(line).is_some() {
+            match current.take() {
+                Some(lines) => blocks.push(lines.join("\n")),
+                None => current = Some(vec![]),
+            }
+        } else if let Some(lines) = current.as_mut() {
+            lines.push(line);
+        }
+    }
+    blocks
+}
+
+/// Reduce markdown to plain prose, such as for reading it aloud, code blocks are dropped
+pub fn strip_markdown(text: &str) -> String {
+    let mut lines = vec![];
+    let mut in_code = false;
+    for line in text.lines() {
+        if detect_code_block(line).is_some() {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            continue;
+        }
+        let line = line.trim_start_matches(|c: char| c == '#' || c == '>' || c.is_whitespace());
+        let line = line
+            .strip_prefix("- ")
+            .or_else(|| line.strip_prefix("* "))
+            .unwrap_or(line);
+        let line: String = line
+            .chars()
+            .filter(|c| !matches!(c, '*' | '_' | '`'))
+            .collect();
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    lines.join("\n")
+}
+
 fn get_code_color(theme: &Theme) -> Color {
     let scope = theme.scopes.iter().find(|v| {
         v.scope
@@ -331,6 +543,60 @@ std::error::Error>> {
         assert!(render.find_syntax("csharp").is_some());
     }
 
+    #[test]
+    fn test_extract_code_blocks() {
+        assert_eq!(
+            extract_code_blocks(TEXT),
+            vec![
+                "use std::fs::File;\n\nfn unzip_file(path: &str, output_dir: &str) -> Result<(), Box<dyn std::error::Error>> {\n    todo!()\n}"
+            ]
+        );
+        assert_eq!(
+            extract_code_blocks("```\na\n```\ntext\n```sh\nb\nc\n```"),
+            vec!["a", "b\nc"]
+        );
+        assert!(extract_code_blocks("no code").is_empty());
+    }
+
+    #[test]
+    fn no_wrap_table() {
+        let options = RenderOptions::default();
+        let mut render = MarkdownRender::init(options).unwrap();
+        render.wrap_width = Some(20);
+        let text = "| name | description |\n|------|-------------|\n| wrap | no, auto, or a column number |";
+        assert_eq!(render.render(text), text);
+    }
+
+    #[test]
+    fn align_table() {
+        let options = RenderOptions {
+            theme: Some(Theme::default()),
+            ..Default::default()
+        };
+        let mut render = MarkdownRender::init(options).unwrap();
+        assert_eq!(render.render_stream("| a | bb |\n|---|---|", false), None);
+        assert_eq!(render.render_stream("| c | d |", false), None);
+        let output = render.render_stream("\ndone", false).unwrap();
+        assert!(output.contains("──┼─"));
+        assert!(output.contains("done"));
+        assert_eq!(render.finish_table(""), None);
+        // The rest of a line that is already printed in part
+        assert!(render.render_stream("      | not a table", true).is_some());
+        assert_eq!(render.finish_table(""), None);
+    }
+
+    #[test]
+    fn test_strip_markdown() {
+        assert_eq!(
+            strip_markdown(TEXT),
+            "To unzip a file in Rust, you can use the zip crate. Here's an example code that shows how to unzip a file:"
+        );
+        assert_eq!(
+            strip_markdown("## Steps\n\n- **first** step\n> quoted"),
+            "Steps\nfirst step\nquoted"
+        );
+    }
+
     #[test]
     fn no_theme() {
         let options = RenderOptions::default();
@@ -359,4 +625,17 @@ std::error::Error>> {
         let output = render.render(TEXT);
         assert_eq!(TEXT_WRAP_ALL, output);
     }
+
+    #[test]
+    fn squeeze_blank() {
+        let options = RenderOptions {
+            squeeze_blank: true,
+            ..Default::default()
+        };
+        let mut render = MarkdownRender::init(options).unwrap();
+        let output = render.render("a\n\n\n\nb\n```\n1\n\n\n2\n```\n\n");
+        assert_eq!(output, "a\n\nb\n```\n1\n\n\n2\n```\n");
+        // The blank line ending the last chunk counts for the next one
+        assert_eq!(render.render_stream("\n\nc\n", false).unwrap(), "c\n");
+    }
 }
@@ -0,0 +1,320 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+
+lazy_static! {
+    static ref SYMBOLS: HashMap<&'static str, &'static str> = {
+        let mut m = HashMap::new();
+        for (name, symbol) in [
+            ("alpha", "α"),
+            ("beta", "β"),
+            ("gamma", "γ"),
+            ("delta", "δ"),
+            ("epsilon", "ε"),
+            ("zeta", "ζ"),
+            ("eta", "η"),
+            ("theta", "θ"),
+            ("iota", "ι"),
+            ("kappa", "κ"),
+            ("lambda", "λ"),
+            ("mu", "μ"),
+            ("nu", "ν"),
+            ("xi", "ξ"),
+            ("pi", "π"),
+            ("rho", "ρ"),
+            ("sigma", "σ"),
+            ("tau", "τ"),
+            ("phi", "φ"),
+            ("chi", "χ"),
+            ("psi", "ψ"),
+            ("omega", "ω"),
+            ("Gamma", "Γ"),
+            ("Delta", "Δ"),
+            ("Theta", "Θ"),
+            ("Lambda", "Λ"),
+            ("Pi", "Π"),
+            ("Sigma", "Σ"),
+            ("Phi", "Φ"),
+            ("Psi", "Ψ"),
+            ("Omega", "Ω"),
+            ("times", "×"),
+            ("cdot", "·"),
+            ("div", "÷"),
+            ("pm", "±"),
+            ("leq", "≤"),
+            ("le", "≤"),
+            ("geq", "≥"),
+            ("ge", "≥"),
+            ("neq", "≠"),
+            ("approx", "≈"),
+            ("equiv", "≡"),
+            ("infty", "∞"),
+            ("sum", "∑"),
+            ("prod", "∏"),
+            ("int", "∫"),
+            ("partial", "∂"),
+            ("nabla", "∇"),
+            ("sqrt", "√"),
+            ("to", "→"),
+            ("rightarrow", "→"),
+            ("leftarrow", "←"),
+            ("Rightarrow", "⇒"),
+            ("in", "∈"),
+            ("notin", "∉"),
+            ("subset", "⊂"),
+            ("cup", "∪"),
+            ("cap", "∩"),
+            ("forall", "∀"),
+            ("exists", "∃"),
+            ("ldots", "…"),
+            ("cdots", "⋯"),
+        ] {
+            m.insert(name, symbol);
+        }
+        m
+    };
+}
+
+const SUPERSCRIPTS: [(char, char); 17] = [
+    ('0', '⁰'),
+    ('1', '¹'),
+    ('2', '²'),
+    ('3', '³'),
+    ('4', '⁴'),
+    ('5', '⁵'),
+    ('6', '⁶'),
+    ('7', '⁷'),
+    ('8', '⁸'),
+    ('9', '⁹'),
+    ('+', '⁺'),
+    ('-', '⁻'),
+    ('=', '⁼'),
+    ('(', '⁽'),
+    (')', '⁾'),
+    ('n', 'ⁿ'),
+    ('i', 'ⁱ'),
+];
+
+const SUBSCRIPTS: [(char, char); 15] = [
+    ('0', '₀'),
+    ('1', '₁'),
+    ('2', '₂'),
+    ('3', '₃'),
+    ('4', '₄'),
+    ('5', '₅'),
+    ('6', '₆'),
+    ('7', '₇'),
+    ('8', '₈'),
+    ('9', '₉'),
+    ('+', '₊'),
+    ('-', '₋'),
+    ('=', '₌'),
+    ('(', '₍'),
+    (')', '₎'),
+];
+
+/// Split a line into `(text, is_math)` segments, the `$` delimiters are dropped from math
+pub fn split_math(line: &str) -> Vec<(String, bool)> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut segments = vec![];
+    let mut text = String::new();
+    let mut in_code = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '`' {
+            in_code = !in_code;
+        } else if c == '$' && !in_code {
+            if let Some((math, end)) = find_math(&chars, i) {
+                if !text.is_empty() {
+                    segments.push((std::mem::take(&mut text), false));
+                }
+                segments.push((math, true));
+                i = end;
+                continue;
+            }
+        }
+        text.push(c);
+        i += 1;
+    }
+    if !text.is_empty() {
+        segments.push((text, false));
+    }
+    segments
+}
+
+/// Find the math span opened at `start`, returning its content and the index after it
+fn find_math(chars: &[char], start: usize) -> Option<(String, usize)> {
+    let display = chars.get(start + 1) == Some(&'$');
+    let open = if display { 2 } else { 1 };
+    let content_start = start + open;
+    let first = *chars.get(content_start)?;
+    if !display && first.is_whitespace() {
+        return None;
+    }
+    let mut i = content_start;
+    while i < chars.len() {
+        if chars[i] == '\\' {
+            i += 2;
+            continue;
+        }
+        if chars[i] == '$' {
+            if display {
+                if chars.get(i + 1) == Some(&'$') && i > content_start {
+                    let math: String = chars[content_start..i].iter().collect();
+                    return Some((math.trim().to_string(), i + 2));
+                }
+                return None;
+            }
+            // `$5 and $10` is money, not math
+            let prev = chars[i - 1];
+            let next = chars.get(i + 1);
+            if i == content_start
+                || prev.is_whitespace()
+                || next.is_some_and(|v| v.is_ascii_digit())
+            {
+                return None;
+            }
+            let math: String = chars[content_start..i].iter().collect();
+            return Some((math, i + 1));
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Convert simple LaTeX, such as greek letters, operators and scripts, to Unicode
+pub fn math_to_unicode(expr: &str) -> String {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut output = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' => {
+                let name: String = chars[i + 1..]
+                    .iter()
+                    .take_while(|v| v.is_ascii_alphabetic())
+                    .collect();
+                if name.is_empty() {
+                    // Escaped char, such as `\{` or `\,`
+                    match chars.get(i + 1) {
+                        Some(',') | Some(';') | Some(' ') => output.push(' '),
+                        Some(v) => output.push(*v),
+                        None => {}
+                    }
+                    i += 2;
+                    continue;
+                }
+                i += 1 + name.len();
+                if name == "frac" {
+                    let (numerator, next) = read_group(&chars, i);
+                    let (denominator, next) = read_group(&chars, next);
+                    output.push_str(&format!(
+                        "{}/{}",
+                        math_to_unicode(&numerator),
+                        math_to_unicode(&denominator)
+                    ));
+                    i = next;
+                } else if let Some(symbol) = SYMBOLS.get(name.as_str()) {
+                    output.push_str(symbol);
+                } else {
+                    output.push('\\');
+                    output.push_str(&name);
+                }
+            }
+            '^' | '_' => {
+                let (group, next) = read_group(&chars, i + 1);
+                let table: &[(char, char)] = if c == '^' { &SUPERSCRIPTS } else { &SUBSCRIPTS };
+                let group = math_to_unicode(&group);
+                let scripted: Option<String> = group
+                    .chars()
+                    .map(|v| table.iter().find(|(k, _)| *k == v).map(|(_, s)| *s))
+                    .collect();
+                match scripted {
+                    Some(scripted) if !group.is_empty() => output.push_str(&scripted),
+                    _ if group.chars().count() == 1 => {
+                        output.push(c);
+                        output.push_str(&group);
+                    }
+                    _ => output.push_str(&format!("{c}({group})")),
+                }
+                i = next;
+            }
+            '{' | '}' => i += 1,
+            _ => {
+                output.push(c);
+                i += 1;
+            }
+        }
+    }
+    output
+}
+
+/// Read a `{...}` group or a single char at `start`
+fn read_group(chars: &[char], start: usize) -> (String, usize) {
+    match chars.get(start) {
+        Some('{') => {
+            let mut depth = 0;
+            for (i, c) in chars.iter().enumerate().skip(start) {
+                match c {
+                    '{' => depth += 1,
+                    '}' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return (chars[start + 1..i].iter().collect(), i + 1);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            (chars[start + 1..].iter().collect(), chars.len())
+        }
+        Some('\\') => {
+            let name: String = chars[start + 1..]
+                .iter()
+                .take_while(|v| v.is_ascii_alphabetic())
+                .collect();
+            (format!("\\{name}"), start + 1 + name.len())
+        }
+        Some(c) => (c.to_string(), start + 1),
+        None => (String::new(), start),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_math() {
+        assert_eq!(
+            split_math("area is $\\pi r^2$ here"),
+            vec![
+                ("area is ".into(), false),
+                ("\\pi r^2".into(), true),
+                (" here".into(), false)
+            ]
+        );
+        assert_eq!(split_math("$$E = mc^2$$"), vec![("E = mc^2".into(), true)]);
+        assert_eq!(
+            split_math("costs $5 or $10"),
+            vec![("costs $5 or $10".into(), false)]
+        );
+        assert_eq!(
+            split_math("`echo $HOME$` and $x$"),
+            vec![("`echo $HOME$` and ".into(), false), ("x".into(), true)]
+        );
+    }
+
+    #[test]
+    fn test_math_to_unicode() {
+        assert_eq!(math_to_unicode("\\pi r^2"), "π r²");
+        assert_eq!(math_to_unicode("x_{12} + y^{n+1}"), "x₁₂ + yⁿ⁺¹");
+        assert_eq!(math_to_unicode("\\frac{a}{b} \\leq \\infty"), "a/b ≤ ∞");
+        assert_eq!(math_to_unicode("e^{ix}"), "e^(ix)");
+        assert_eq!(
+            math_to_unicode("\\alpha \\times \\unknown"),
+            "α × \\unknown"
+        );
+    }
+}
@@ -1,9 +1,16 @@
 mod cmd;
+mod link;
 mod markdown;
+mod math;
+mod pager;
 mod repl;
+mod spinner;
+mod table;
 
 use self::cmd::cmd_render_stream;
-pub use self::markdown::{MarkdownRender, RenderOptions};
+pub use self::link::visible_width;
+pub use self::markdown::{extract_code_blocks, strip_markdown, MarkdownRender, RenderOptions};
+pub use self::pager::page_output;
 use self::repl::repl_render_stream;
 
 use crate::client::Client;
@@ -14,7 +21,10 @@ use crate::repl::{ReplyStreamHandler, SharedAbortSignal};
 use anyhow::Result;
 use crossbeam::channel::unbounded;
 use crossbeam::sync::WaitGroup;
+use is_terminal::IsTerminal;
+use std::io::{stdin, stdout};
 use std::thread::spawn;
+use std::time::Duration;
 
 pub fn render_stream(
     input: &str,
@@ -25,26 +35,45 @@ pub fn render_stream(
     wg: WaitGroup,
 ) -> Result<String> {
     let render_options = config.read().get_render_options()?;
+    let show_stats = config.read().show_stream_stats && stdout().is_terminal();
+    let show_reading_time = config.read().show_reading_time && stdout().is_terminal();
+    let flush_interval = Duration::from_millis(config.read().stream_flush_interval);
+    // Repainting needs a terminal on both ends, to move the cursor and to catch the keys
+    let repaint =
+        repl || (config.read().live_render && stdin().is_terminal() && stdout().is_terminal());
+    // Nothing to erase the spinner from when stdout is piped
+    let spinner = stdout().is_terminal();
     let mut stream_handler = {
         let (tx, rx) = unbounded();
         let abort_clone = abort.clone();
         spawn(move || {
             let run = move || {
-                if repl {
+                if repaint {
                     let mut render = MarkdownRender::init(render_options)?;
                     repl_render_stream(&rx, &mut render, &abort)
                 } else {
                     let mut render = MarkdownRender::init(render_options)?;
-                    cmd_render_stream(&rx, &mut render, &abort)
+                    cmd_render_stream(&rx, &mut render, &abort, spinner)
                 }
             };
             if let Err(err) = run() {
                 let err = format!("{err:?}");
-                print_now!("{}\n\n", err.trim());
+                if repl {
+                    print_now!("{}\n\n", err.trim());
+                } else {
+                    // Keep stdout clean for pipelines
+                    eprintln!("Error: {}", err.trim());
+                }
             }
             drop(wg);
         });
-        ReplyStreamHandler::new(tx, abort_clone)
+        ReplyStreamHandler::new(
+            tx,
+            abort_clone,
+            show_stats,
+            show_reading_time,
+            flush_interval,
+        )
     };
     client.send_message_streaming(input, &mut stream_handler)?;
     let buffer = stream_handler.get_buffer();
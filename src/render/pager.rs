@@ -0,0 +1,65 @@
+use super::{visible_width, MarkdownRender, RenderOptions};
+
+use anyhow::{anyhow, Context, Result};
+use crossterm::terminal;
+use is_terminal::IsTerminal;
+use std::{
+    env,
+    io::{stdout, Write},
+    process::{Command, Stdio},
+};
+
+const DEFAULT_PAGER: &str = "less -R";
+
+/// Hand the rendered text to the pager if it does not fit the terminal.
+pub fn page_output(text: &str, render_options: RenderOptions) -> Result<()> {
+    if !stdout().is_terminal() {
+        return Ok(());
+    }
+    let (columns, rows) = match terminal::size() {
+        Ok(v) => v,
+        Err(_) => return Ok(()),
+    };
+    let mut render = MarkdownRender::init(render_options)?;
+    let output = render.render(text);
+    if count_rows(&output, columns) < rows as usize {
+        return Ok(());
+    }
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PAGER.into());
+    let mut parts = pager.split_whitespace();
+    let program = parts.next().ok_or_else(|| anyhow!("Invalid pager"))?;
+    let mut child = Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run pager `{pager}`"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager may quit before reading everything
+        let _ = stdin.write_all(output.as_bytes());
+    }
+    child.wait()?;
+    Ok(())
+}
+
+fn count_rows(text: &str, columns: u16) -> usize {
+    let columns = (columns as usize).max(1);
+    text.split('\n')
+        .map(|line| visible_width(line).max(1).div_ceil(columns))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_rows() {
+        assert_eq!(count_rows("", 80), 1);
+        assert_eq!(count_rows("a\nb\n", 80), 3);
+        assert_eq!(count_rows(&"a".repeat(81), 80), 2);
+        assert_eq!(count_rows("\u{1b}[31mred\u{1b}[0m", 3), 1);
+    }
+}
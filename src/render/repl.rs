@@ -1,4 +1,4 @@
-use super::MarkdownRender;
+use super::{spinner::Spinner, visible_width, MarkdownRender};
 
 use crate::repl::{ReplyStreamEvent, SharedAbortSignal};
 use crate::utils::split_line_tail;
@@ -9,13 +9,12 @@ use crossterm::{
     cursor,
     event::{self, Event, KeyCode, KeyModifiers},
     queue, style,
-    terminal::{self, disable_raw_mode, enable_raw_mode},
+    terminal::{self, disable_raw_mode, enable_raw_mode, ClearType},
 };
 use std::{
     io::{self, Stdout, Write},
     time::{Duration, Instant},
 };
-use textwrap::core::display_width;
 
 pub fn repl_render_stream(
     rx: &Receiver<ReplyStreamEvent>,
@@ -25,7 +24,12 @@ pub fn repl_render_stream(
     enable_raw_mode()?;
     let mut stdout = io::stdout();
 
-    let ret = repl_render_stream_inner(rx, render, abort, &mut stdout);
+    let mut spinner = Some(Spinner::new());
+    let mut ret = repl_render_stream_inner(rx, render, abort, &mut stdout, &mut spinner);
+    // Still there if the reply was aborted before it began
+    if let Some(mut spinner) = spinner {
+        ret = ret.and(spinner.clear(&mut stdout));
+    }
 
     disable_raw_mode()?;
 
@@ -37,10 +41,12 @@ fn repl_render_stream_inner(
     render: &mut MarkdownRender,
     abort: &SharedAbortSignal,
     writer: &mut Stdout,
+    spinner: &mut Option<Spinner>,
 ) -> Result<()> {
     let mut last_tick = Instant::now();
     let tick_rate = Duration::from_millis(50);
     let mut buffer = String::new();
+    let mut footers = vec![];
     let columns = terminal::size()?.0;
 
     let mut clear_rows = 0;
@@ -50,12 +56,15 @@ fn repl_render_stream_inner(
         }
 
         if let Ok(evt) = rx.try_recv() {
+            if let Some(mut spinner) = spinner.take() {
+                spinner.clear(writer)?;
+            }
             match evt {
                 ReplyStreamEvent::Text(text) => {
                     let (col, mut row) = cursor::position()?;
 
                     // fix unexpected duplicate lines on kitty, see https://github.com/sigoden/aichat/issues/105
-                    if col == 0 && row > 0 && display_width(&buffer) == columns as usize {
+                    if col == 0 && row > 0 && visible_width(&buffer) == columns as usize {
                         row -= 1;
                     }
 
@@ -74,8 +83,13 @@ fn repl_render_stream_inner(
                         let text = format!("{buffer}{text}");
                         let (head, tail) = split_line_tail(&text);
                         buffer = tail.to_string();
-                        let output = render.render(head);
-                        print_block(writer, &output, columns)?;
+                        match render.render_stream(head, false) {
+                            Some(output) => {
+                                print_block(writer, &output, columns)?;
+                            }
+                            // The rows of a table are held, drop the partial row shown so far
+                            None => queue!(writer, terminal::Clear(ClearType::FromCursorDown))?,
+                        }
                         queue!(writer, style::Print(&buffer),)?;
                         clear_rows = 0;
                     } else {
@@ -87,7 +101,7 @@ fn repl_render_stream_inner(
                             queue!(writer, style::Print(&tail),)?;
                         } else {
                             queue!(writer, style::Print(&output))?;
-                            let buffer_width = display_width(&output) as u16;
+                            let buffer_width = visible_width(&output) as u16;
                             let need_rows = (buffer_width + columns - 1) / columns;
                             clear_rows = need_rows.saturating_sub(1);
                         }
@@ -95,12 +109,36 @@ fn repl_render_stream_inner(
 
                     writer.flush()?;
                 }
+                ReplyStreamEvent::Footer(text) => {
+                    footers.push(text);
+                }
                 ReplyStreamEvent::Done => {
+                    if let Some(output) = render.finish_table(&buffer) {
+                        let (_, row) = cursor::position()?;
+                        queue!(
+                            writer,
+                            cursor::MoveTo(0, row.saturating_sub(clear_rows)),
+                            terminal::Clear(ClearType::FromCursorDown),
+                        )?;
+                        let (head, tail) = split_line_tail(&output);
+                        if !head.is_empty() {
+                            print_block(writer, head, columns)?;
+                        }
+                        queue!(writer, style::Print(tail))?;
+                    }
                     #[cfg(target_os = "windows")]
                     let eol = "\n\n";
                     #[cfg(not(target_os = "windows"))]
                     let eol = "\n";
                     queue!(writer, style::Print(eol))?;
+                    for footer in &footers {
+                        queue!(
+                            writer,
+                            cursor::MoveToColumn(0),
+                            style::Print(footer),
+                            style::Print("\n"),
+                        )?;
+                    }
                     writer.flush()?;
 
                     break;
@@ -109,6 +147,10 @@ fn repl_render_stream_inner(
             continue;
         }
 
+        if let Some(spinner) = spinner.as_mut() {
+            spinner.tick(writer)?;
+        }
+
         let timeout = tick_rate
             .checked_sub(last_tick.elapsed())
             .unwrap_or_else(|| Duration::from_secs(0));
@@ -123,6 +165,12 @@ fn repl_render_stream_inner(
                         abort.set_ctrld();
                         return Ok(());
                     }
+                    KeyCode::Esc => {
+                        abort.set_esc();
+                        queue!(writer, style::Print("\r\n"))?;
+                        writer.flush()?;
+                        return Ok(());
+                    }
                     _ => {}
                 }
             }
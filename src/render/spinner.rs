@@ -0,0 +1,52 @@
+use anyhow::Result;
+use crossterm::{
+    queue,
+    style::{self, Stylize},
+    terminal::{self, ClearType},
+};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+const FRAMES: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shows that the request is in flight until the first event of the reply arrives
+pub struct Spinner {
+    index: usize,
+    drawn_at: Option<Instant>,
+}
+
+impl Spinner {
+    pub fn new() -> Self {
+        Self {
+            index: 0,
+            drawn_at: None,
+        }
+    }
+
+    /// Draw the next frame over the previous one once it is due
+    pub fn tick(&mut self, writer: &mut impl Write) -> Result<()> {
+        if self.drawn_at.is_some_and(|v| v.elapsed() < FRAME_INTERVAL) {
+            return Ok(());
+        }
+        let frame = format!("{} thinking…", FRAMES[self.index % FRAMES.len()]);
+        queue!(writer, style::Print('\r'), style::Print(frame.dim()))?;
+        writer.flush()?;
+        self.index += 1;
+        self.drawn_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Erase the spinner, if it was drawn, so the reply starts at the beginning of the line
+    pub fn clear(&mut self, writer: &mut impl Write) -> Result<()> {
+        if self.drawn_at.take().is_some() {
+            queue!(
+                writer,
+                style::Print('\r'),
+                terminal::Clear(ClearType::CurrentLine)
+            )?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
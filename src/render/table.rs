@@ -0,0 +1,146 @@
+use textwrap::core::display_width;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    Left,
+    Center,
+    Right,
+}
+
+pub fn is_table_row(line: &str) -> bool {
+    line.trim_start().starts_with('|')
+}
+
+/// Split a row into trimmed cells, the trailing pipe is optional and `\|` is kept as a pipe
+pub fn split_row(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = match line.strip_suffix('|') {
+        Some(v) if !v.ends_with('\\') => v,
+        _ => line,
+    };
+    let mut cells = vec![];
+    let mut cell = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'|') => {
+                cell.push('|');
+                chars.next();
+            }
+            '|' => cells.push(std::mem::take(&mut cell).trim().to_string()),
+            _ => cell.push(c),
+        }
+    }
+    cells.push(cell.trim().to_string());
+    cells
+}
+
+/// Parse the delimiter row, such as `|:---|:---:|---:|`
+pub fn parse_alignments(line: &str) -> Option<Vec<Alignment>> {
+    split_row(line)
+        .iter()
+        .map(|cell| {
+            let left = cell.starts_with(':');
+            let right = cell.ends_with(':');
+            let dashes = cell.trim_matches(':');
+            if dashes.is_empty() || !dashes.chars().all(|c| c == '-') {
+                return None;
+            }
+            match (left, right) {
+                (true, true) => Some(Alignment::Center),
+                (false, true) => Some(Alignment::Right),
+                _ => Some(Alignment::Left),
+            }
+        })
+        .collect()
+}
+
+/// Lay out a table with padded columns, returning `None` if the lines are not a table
+pub fn format_table(lines: &[&str]) -> Option<Vec<String>> {
+    let (header, delimiter, body) = match lines {
+        [header, delimiter, body @ ..] => (header, delimiter, body),
+        _ => return None,
+    };
+    let alignments = parse_alignments(delimiter)?;
+    let mut rows = vec![split_row(header)];
+    rows.extend(body.iter().map(|v| split_row(v)));
+    // Ragged rows are padded with empty cells
+    let columns = rows.iter().map(|v| v.len()).max().unwrap_or_default();
+    let widths: Vec<usize> = (0..columns)
+        .map(|i| {
+            rows.iter()
+                .filter_map(|v| v.get(i))
+                .map(|v| display_width(v))
+                .max()
+                .unwrap_or_default()
+                .max(1)
+        })
+        .collect();
+    let format_row = |row: &[String]| {
+        widths
+            .iter()
+            .enumerate()
+            .map(|(i, width)| {
+                let cell = row.get(i).map(|v| v.as_str()).unwrap_or_default();
+                let alignment = alignments.get(i).copied().unwrap_or(Alignment::Left);
+                pad(cell, *width, alignment)
+            })
+            .collect::<Vec<String>>()
+            .join(" │ ")
+            .trim_end()
+            .to_string()
+    };
+    let mut output = vec![format_row(&rows[0])];
+    output.push(
+        widths
+            .iter()
+            .map(|v| "─".repeat(*v))
+            .collect::<Vec<String>>()
+            .join("─┼─"),
+    );
+    output.extend(rows[1..].iter().map(|v| format_row(v)));
+    Some(output)
+}
+
+fn pad(cell: &str, width: usize, alignment: Alignment) -> String {
+    let space = width.saturating_sub(display_width(cell));
+    let (left, right) = match alignment {
+        Alignment::Left => (0, space),
+        Alignment::Center => (space / 2, space - space / 2),
+        Alignment::Right => (space, 0),
+    };
+    format!("{}{cell}{}", " ".repeat(left), " ".repeat(right))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_row() {
+        assert_eq!(split_row("| a | b |"), vec!["a", "b"]);
+        assert_eq!(split_row("| a | b"), vec!["a", "b"]);
+        assert_eq!(split_row("| a \\| b | c |"), vec!["a | b", "c"]);
+    }
+
+    #[test]
+    fn test_format_table() {
+        let lines = [
+            "| name | qty | note |",
+            "|:---|:---:|---:|",
+            "| apple | 3 | red |",
+            "| kiwi | 12",
+        ];
+        assert_eq!(
+            format_table(&lines).unwrap(),
+            vec![
+                "name  │ qty │ note",
+                "──────┼─────┼─────",
+                "apple │  3  │  red",
+                "kiwi  │ 12  │",
+            ]
+        );
+        assert!(format_table(&["| a | b |", "| c | d |"]).is_none());
+    }
+}
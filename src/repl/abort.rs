@@ -8,6 +8,7 @@ pub type SharedAbortSignal = Arc<AbortSignal>;
 pub struct AbortSignal {
     ctrlc: AtomicBool,
     ctrld: AtomicBool,
+    esc: AtomicBool,
 }
 
 impl AbortSignal {
@@ -15,6 +16,7 @@ impl AbortSignal {
         Arc::new(Self {
             ctrlc: AtomicBool::new(false),
             ctrld: AtomicBool::new(false),
+            esc: AtomicBool::new(false),
         })
     }
 
@@ -25,6 +27,9 @@ impl AbortSignal {
         if self.aborted_ctrld() {
             return true;
         }
+        if self.aborted_esc() {
+            return true;
+        }
         false
     }
 
@@ -36,9 +41,15 @@ impl AbortSignal {
         self.ctrld.load(Ordering::SeqCst)
     }
 
+    /// Esc only stops the current reply, it never leads to exiting the REPL
+    pub fn aborted_esc(&self) -> bool {
+        self.esc.load(Ordering::SeqCst)
+    }
+
     pub fn reset(&self) {
         self.ctrlc.store(false, Ordering::SeqCst);
         self.ctrld.store(false, Ordering::SeqCst);
+        self.esc.store(false, Ordering::SeqCst);
     }
 
     pub fn set_ctrlc(&self) {
@@ -48,4 +59,8 @@ impl AbortSignal {
     pub fn set_ctrld(&self) {
         self.ctrld.store(true, Ordering::SeqCst);
     }
+
+    pub fn set_esc(&self) {
+        self.esc.store(true, Ordering::SeqCst);
+    }
 }
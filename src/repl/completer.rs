@@ -0,0 +1,93 @@
+use super::REPL_COMMANDS;
+
+use crate::config::SharedConfig;
+
+use reedline::{Completer, Span, Suggestion};
+
+/// Completes the REPL commands, then their arguments from the current roles, models and sessions
+pub struct ReplCompleter {
+    config: SharedConfig,
+}
+
+impl ReplCompleter {
+    pub fn new(config: SharedConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl Completer for ReplCompleter {
+    fn complete(&mut self, line: &str, pos: usize) -> Vec<Suggestion> {
+        let line = &line[..pos];
+        if !line.starts_with('.') {
+            return vec![];
+        }
+        let (command, arg) = match line.split_once(' ') {
+            None => {
+                return REPL_COMMANDS
+                    .iter()
+                    .filter(|(name, _)| !name.contains(' ') && name.starts_with(line))
+                    .map(|(name, description)| {
+                        suggestion(name, Some(description), Span::new(0, pos))
+                    })
+                    .collect();
+            }
+            Some(v) => v,
+        };
+        let span = Span::new(pos - arg.len(), pos);
+        let mut suggestions: Vec<Suggestion> = REPL_COMMANDS
+            .iter()
+            .filter_map(|(name, description)| {
+                let sub = name.strip_prefix(command)?.strip_prefix(' ')?;
+                Some((sub, description))
+            })
+            .filter(|(sub, _)| sub.starts_with(arg))
+            .map(|(sub, description)| suggestion(sub, Some(description), span))
+            .collect();
+        suggestions.extend(
+            self.config
+                .read()
+                .repl_completions(command)
+                .iter()
+                .filter(|v| v.starts_with(arg))
+                .map(|v| suggestion(v, None, span)),
+        );
+        suggestions
+    }
+}
+
+fn suggestion(value: &str, description: Option<&str>, span: Span) -> Suggestion {
+    Suggestion {
+        value: value.to_string(),
+        description: description.map(|v| v.to_string()),
+        extra: None,
+        span,
+        append_whitespace: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    use parking_lot::RwLock;
+    use std::sync::Arc;
+
+    fn values(line: &str) -> Vec<String> {
+        let config = Arc::new(RwLock::new(Config::default()));
+        ReplCompleter::new(config)
+            .complete(line, line.len())
+            .into_iter()
+            .map(|v| v.value)
+            .collect()
+    }
+
+    #[test]
+    fn test_complete() {
+        assert_eq!(values(".ses"), vec![".session"]);
+        assert_eq!(values(".info s"), vec!["session"]);
+        assert_eq!(values(".set stream t"), vec!["stream true"]);
+        assert!(values(".model openai:").contains(&"openai:gpt-4".to_string()));
+        assert!(values("hello").is_empty());
+    }
+}
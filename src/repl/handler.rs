@@ -1,37 +1,111 @@
-use crate::client::init_client;
-use crate::config::SharedConfig;
+use crate::client::{
+    init_client, list_models, send_message_concurrently, send_with_fallback, Client,
+};
+use crate::config::{
+    parse_template_text, Config, SharedConfig, DEFAULT_TEMPERATURE, SUMMARIZE_PROMPT,
+};
 use crate::print_now;
-use crate::render::{render_stream, MarkdownRender};
-use std::fs;
-use std::io::Read;
+use crate::render::{
+    extract_code_blocks, page_output, render_stream, strip_markdown, MarkdownRender,
+};
+use crate::utils::{
+    count_tokens, count_words, diff_lines, edit_text, image_data_url, osc52_sequence,
+    read_file_range, speak, DiffLine,
+};
+use is_terminal::IsTerminal;
+use std::fs::{self, create_dir_all};
+use std::io::stdout;
+use std::path::Path;
 
 use super::abort::SharedAbortSignal;
 
 use anyhow::{bail, Context, Result};
 use arboard::Clipboard;
-use crossbeam::channel::Sender;
+use crossbeam::channel::{unbounded, Sender};
 use crossbeam::sync::WaitGroup;
+use crossterm::cursor::MoveTo;
+use crossterm::execute;
+use crossterm::style::Stylize;
+use crossterm::terminal::{Clear, ClearType};
+use inquire::Confirm;
+use parking_lot::{Mutex, RwLock};
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Shorter replies get no reading time
+const READING_TIME_MIN_WORDS: usize = 100;
+const WORDS_PER_MINUTE: usize = 200;
+/// Sent to every model by `.benchmark`, long enough for a steady speed
+const BENCHMARK_PROMPT: &str = "Explain in about 200 words how a hash map works.";
 
 pub enum ReplCmd {
     Submit(String),
+    SubmitWithImage(String, Vec<String>),
+    Edit(Option<String>),
     Info,
+    TokenUsage,
+    Count(String),
+    Cost,
     RoleInfo,
+    PromptInfo,
+    ShowContext,
     SessionInfo,
-    SetModel(String),
-    SetRole(String),
+    SetModel(String, bool),
+    CycleModel(bool),
+    ListModels,
+    SetRole(String, Vec<(String, String)>),
+    SaveRole(String),
+    EditRole(String),
+    CloneRole(String, String),
+    DefaultRole(String),
     ExitRole,
     StartSession(Option<String>),
+    ExportSession(String),
+    ImportSession(String),
+    Replay(String),
+    MergeSession(String),
+    LockSession(bool),
+    ListSessions,
+    RenameSession(String),
+    Fork(String),
     ExitSession,
     Set(String),
     Copy,
-    ReadFile(String),
+    Paste,
+    ShowLast,
+    CopyBlock(Option<usize>),
+    ReadFile(Vec<String>),
+    SaveFile(String),
+    Retry,
+    Regenerate(Option<f64>),
+    Undo,
+    Pin(usize, bool),
+    Diff,
+    Compare(Vec<String>, Option<String>),
+    Benchmark(Vec<String>),
+    Clear(bool),
+    Compress(usize),
+    Vars(String),
+    Search(String),
+    ListMacros,
+    SaveMacro(String),
+    ListTemplates,
+    SaveTemplate(String, String),
+    UseTemplate(String, String),
 }
 
+/// Model names paired with their max tokens, if known
+type ModelList = Vec<(String, Option<usize>)>;
+
 pub struct ReplCmdHandler {
     config: SharedConfig,
     abort: SharedAbortSignal,
     clipboard: std::result::Result<RefCell<Clipboard>, arboard::Error>,
+    /// Models fetched from each client, kept for the rest of the REPL
+    models: RefCell<HashMap<String, ModelList>>,
 }
 
 impl ReplCmdHandler {
@@ -41,6 +115,7 @@ impl ReplCmdHandler {
             config,
             abort,
             clipboard,
+            models: RefCell::new(HashMap::new()),
         })
     }
 
@@ -50,51 +125,270 @@ impl ReplCmdHandler {
                 if input.is_empty() {
                     return Ok(());
                 }
+                let input = match self.config.read().session.as_ref() {
+                    Some(session) if input.contains("{{") => {
+                        let (input, undefined) = session.apply_variables(&input);
+                        for name in undefined {
+                            let warning = format!("Warning: Undefined variable `{name}`");
+                            eprintln!("{}", warning.yellow());
+                        }
+                        input
+                    }
+                    _ => input,
+                };
+                self.config.write().fill_role_variables()?;
+                if !self.config.read().check_context_window(&input)? {
+                    return Ok(());
+                }
                 self.config.read().maybe_print_send_tokens(&input);
-                let wg = WaitGroup::new();
-                let client = init_client(self.config.clone())?;
-                let ret = render_stream(
-                    &input,
-                    client.as_ref(),
-                    &self.config,
-                    true,
-                    self.abort.clone(),
-                    wg.clone(),
-                );
-                wg.wait();
-                let buffer = ret?;
-                self.config.write().save_message(&input, &buffer)?;
+                let buffer = send_with_fallback(&self.config, |client| {
+                    if self.config.read().use_stream() {
+                        let wg = WaitGroup::new();
+                        let ret = render_stream(
+                            &input,
+                            client,
+                            &self.config,
+                            true,
+                            self.abort.clone(),
+                            wg.clone(),
+                        );
+                        wg.wait();
+                        ret
+                    } else {
+                        let buffer = client.send_message(&input, self.abort.clone())?;
+                        let buffer = self.config.read().filter_reply(&buffer)?;
+                        let render_options = self.config.read().get_render_options()?;
+                        let mut markdown_render = MarkdownRender::init(render_options)?;
+                        print_now!("{}\n\n", markdown_render.render(&buffer).trim_end());
+                        Ok(buffer)
+                    }
+                })?;
+                if self.config.read().use_pager && !self.abort.aborted() {
+                    let render_options = self.config.read().get_render_options()?;
+                    page_output(&buffer, render_options)?;
+                }
+                let truncated = self.abort.aborted();
+                self.config
+                    .write()
+                    .save_message(&input, &buffer, truncated)?;
                 if self.config.read().auto_copy {
                     let _ = self.copy(&buffer);
                 }
+                let tts_command = self.config.read().tts_command.clone();
+                if let Some(command) = tts_command {
+                    if self.abort.aborted() || buffer.is_empty() {
+                        return Ok(());
+                    }
+                    if let Err(err) = speak(&command, &strip_markdown(&buffer)) {
+                        eprintln!("{}", format!("Warning: {err:#}").yellow());
+                    }
+                }
+            }
+            ReplCmd::Edit(text) => {
+                let text = text.unwrap_or_default();
+                let input = edit_text(&text)?;
+                if input.trim().is_empty() {
+                    return Ok(());
+                }
+                self.handle(ReplCmd::Submit(input))?;
+            }
+            ReplCmd::SubmitWithImage(input, paths) => {
+                let model_info = self.config.read().model_info.clone();
+                if !model_info.vision {
+                    bail!(
+                        "{} doesn't accept images, switch to a vision model first",
+                        model_info.stringify()
+                    );
+                }
+                let mut images = vec![];
+                for path in &paths {
+                    images.push(image_data_url(path)?);
+                }
+                // Only for this input, the session keeps the text alone
+                self.config.write().images = images;
+                let ret = self.handle(ReplCmd::Submit(input));
+                self.config.write().images.clear();
+                ret?;
             }
             ReplCmd::Info => {
                 let output = self.config.read().info()?;
                 print_now!("{}\n\n", output.trim_end());
             }
-            ReplCmd::SetModel(name) => {
+            ReplCmd::TokenUsage => {
+                let output = self.config.read().token_usage();
+                print_now!("{}\n\n", output.trim_end());
+            }
+            ReplCmd::Count(text) => {
+                let (label, text) = match text.strip_prefix('@') {
+                    Some(spec) => read_file_range(spec)?,
+                    None => ("text".into(), text),
+                };
+                let tokens = count_tokens(&text);
+                print_now!("{label}: {tokens} tokens (cl100k_base)\n\n");
+            }
+            ReplCmd::Cost => {
+                let output = self.config.read().cost()?;
+                print_now!("{}\n\n", output.trim_end());
+            }
+            ReplCmd::SetModel(name, force) => {
+                if !force {
+                    self.config.read().guard_model_lock()?;
+                }
                 self.config.write().set_model(&name)?;
                 print_now!("\n");
             }
-            ReplCmd::SetRole(name) => {
-                self.config.write().set_role(&name)?;
+            ReplCmd::CycleModel(forward) => {
+                self.config.read().guard_model_lock()?;
+                self.config.write().cycle_model(forward)?;
+                print_now!("{}\n\n", self.config.read().model_info.stringify());
+            }
+            ReplCmd::ListModels => {
+                let output = self
+                    .list_models()?
+                    .into_iter()
+                    .map(|(name, max_tokens)| {
+                        let max_tokens = max_tokens
+                            .map(|v| v.to_string())
+                            .unwrap_or_else(|| "-".into());
+                        format!("{name:<40}{max_tokens}")
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                print_now!("{}\n\n", output);
+            }
+            ReplCmd::SetRole(name, variables) => {
+                self.config.write().set_role(&name, &variables)?;
                 print_now!("\n");
             }
-            ReplCmd::RoleInfo => {
-                if let Some(role) = &self.config.read().role {
-                    print_now!("{}\n\n", role.info()?);
+            ReplCmd::SaveRole(args) => {
+                let mut force = false;
+                let mut name = None;
+                for arg in args.split_whitespace() {
+                    if arg == "--force" {
+                        force = true;
+                    } else {
+                        name = Some(arg);
+                    }
+                }
+                let name = match name {
+                    Some(v) => v,
+                    None => bail!("Usage: .role save <name> [--force]"),
+                };
+                self.config.write().save_role(name, force)?;
+                print_now!("\n");
+            }
+            ReplCmd::EditRole(name) => {
+                let definition = self.config.read().role_definition(&name)?;
+                let content = edit_text(&definition)?;
+                if content.trim() != definition.trim() {
+                    self.config.write().edit_role(&name, &content)?;
+                }
+                print_now!("\n");
+            }
+            ReplCmd::CloneRole(base, name) => {
+                self.config.write().clone_role(&base, &name)?;
+                let edit = Confirm::new(&format!("Edit role '{name}' now?"))
+                    .with_default(false)
+                    .prompt()?;
+                if edit {
+                    self.handle(ReplCmd::EditRole(name))?;
                 } else {
-                    bail!("No role")
+                    print_now!("\n");
                 }
             }
+            ReplCmd::PromptInfo => {
+                let output = self.config.read().prompt_info()?;
+                print_now!("{}\n\n", output.trim_end());
+            }
+            ReplCmd::ShowContext => {
+                let output = self.config.read().context_info()?;
+                print_now!("{}\n\n", output);
+            }
+            ReplCmd::RoleInfo => {
+                let output = self.config.read().role_info()?;
+                print_now!("{}\n\n", output);
+            }
+            ReplCmd::DefaultRole(name) => match name.as_str() {
+                "" => {
+                    let name = self.config.read().default_role.clone();
+                    print_now!("{}\n\n", name.as_deref().unwrap_or("No default role"));
+                }
+                "--unset" => {
+                    self.config.write().set_default_role(None)?;
+                    print_now!("Unset the default role\n\n");
+                }
+                _ => {
+                    self.config.write().set_default_role(Some(&name))?;
+                    print_now!("Set the default role to '{name}'\n\n");
+                }
+            },
             ReplCmd::ExitRole => {
-                self.config.write().clear_role()?;
+                self.config.write().exit_role()?;
                 print_now!("\n");
             }
             ReplCmd::StartSession(name) => {
+                let name = name.or_else(Config::last_session);
                 self.config.write().start_session(&name)?;
                 print_now!("\n");
             }
+            ReplCmd::ExportSession(args) => {
+                match args.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                    [name, path] => {
+                        self.config.read().export_session(name, Path::new(path))?;
+                        print_now!("Exported session '{name}' to {path}\n\n");
+                    }
+                    _ => bail!("Usage: .session export <name> <path>"),
+                }
+            }
+            ReplCmd::ImportSession(args) => {
+                let (path, name) = match args.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                    [path] => (*path, None),
+                    [path, name] => (*path, Some(*name)),
+                    _ => bail!("Usage: .session import <path> [<name>]"),
+                };
+                let name = self.config.read().import_session(Path::new(path), name)?;
+                print_now!("Imported session '{name}'\n\n");
+            }
+            ReplCmd::Replay(name) => {
+                let session = self.config.read().read_session(&name)?;
+                if session.exchanges() == 0 {
+                    bail!("Empty session '{name}'");
+                }
+                let render_options = self.config.read().get_render_options()?;
+                let mut markdown_render = MarkdownRender::init(render_options)?;
+                print_now!(
+                    "{}\n\n",
+                    session.render_messages(&mut markdown_render).trim_end()
+                );
+            }
+            ReplCmd::LockSession(locked) => {
+                self.config.write().lock_session(locked)?;
+                if locked {
+                    let model = self.config.read().model_info.stringify();
+                    print_now!("Locked the session to {model}\n\n");
+                } else {
+                    print_now!("Unlocked the session\n\n");
+                }
+            }
+            ReplCmd::ListSessions => {
+                let output = self.config.read().sessions_info()?;
+                print_now!("{}\n\n", output);
+            }
+            ReplCmd::RenameSession(name) => {
+                self.config.write().rename_session(&name)?;
+                print_now!("\n");
+            }
+            ReplCmd::MergeSession(name) => {
+                let conflicts = self.config.write().merge_session(&name)?;
+                for conflict in conflicts {
+                    eprintln!("{}", format!("Warning: {conflict}").yellow());
+                }
+                print_now!("\n");
+            }
+            ReplCmd::Fork(name) => {
+                self.config.write().fork_session(&name)?;
+                print_now!("\n");
+            }
             ReplCmd::SessionInfo => {
                 if let Some(session) = &self.config.read().session {
                     let render_options = self.config.read().get_render_options()?;
@@ -112,6 +406,15 @@ impl ReplCmdHandler {
                 self.config.write().update(&input)?;
                 print_now!("\n");
             }
+            ReplCmd::ShowLast => {
+                let reply = match self.config.read().last_message.as_ref() {
+                    Some((_, reply)) => reply.clone(),
+                    None => bail!("No last message"),
+                };
+                let render_options = self.config.read().get_render_options()?;
+                let mut markdown_render = MarkdownRender::init(render_options)?;
+                print_now!("{}\n\n", markdown_render.render(&reply).trim_end());
+            }
             ReplCmd::Copy => {
                 let reply = self
                     .config
@@ -124,40 +427,428 @@ impl ReplCmdHandler {
                     .with_context(|| "Failed to copy the last output")?;
                 print_now!("\n");
             }
-            ReplCmd::ReadFile(file) => {
-                let mut contents = String::new();
-                let mut file = fs::File::open(file).with_context(|| "Unable to open file")?;
-                file.read_to_string(&mut contents)
-                    .with_context(|| "Unable to read file")?;
+            ReplCmd::Paste => {
+                let text = self
+                    .paste()
+                    .with_context(|| "Failed to read the clipboard")?;
+                if text.trim().is_empty() {
+                    bail!("The clipboard is empty");
+                }
+                self.handle(ReplCmd::Submit(text))?;
+            }
+            ReplCmd::CopyBlock(index) => {
+                let reply = self
+                    .config
+                    .read()
+                    .last_message
+                    .as_ref()
+                    .map(|v| v.1.clone())
+                    .unwrap_or_default();
+                let blocks = extract_code_blocks(&reply);
+                if blocks.is_empty() {
+                    bail!("No code block found");
+                }
+                let index = index.unwrap_or(1);
+                let block = match index.checked_sub(1).and_then(|i| blocks.get(i)) {
+                    Some(v) => v,
+                    None => bail!("No code block #{index}, only {} found", blocks.len()),
+                };
+                self.copy(block)
+                    .with_context(|| "Failed to copy the code block")?;
+                print_now!("\n");
+            }
+            ReplCmd::ReadFile(files) => {
+                let mut parts = vec![];
+                for spec in &files {
+                    parts.push(read_file_range(spec)?);
+                }
+                let contents = match parts.as_slice() {
+                    [(header, contents)] if header == &files[0] => contents.clone(),
+                    _ => parts
+                        .into_iter()
+                        .map(|(header, contents)| format!("----- {header} -----\n{contents}"))
+                        .collect::<Vec<String>>()
+                        .join("\n"),
+                };
                 self.handle(ReplCmd::Submit(contents))?;
             }
+            ReplCmd::Retry => {
+                self.resubmit()?;
+            }
+            ReplCmd::Regenerate(temperature) => {
+                let current = self.config.read().get_temperature();
+                let temperature = temperature
+                    .unwrap_or_else(|| current.unwrap_or(DEFAULT_TEMPERATURE) + 0.3)
+                    .clamp(0.0, 2.0);
+                // Only for this reply, the session keeps its own temperature
+                self.config.write().temperature = Some(temperature);
+                let ret = self.resubmit();
+                self.config.write().temperature = current;
+                ret?;
+            }
+            ReplCmd::Compare(models, input) => {
+                let last_input = self.config.read().last_message.clone().map(|v| v.0);
+                let input = match input.or(last_input) {
+                    Some(v) => v,
+                    None => bail!("No input to compare, pass a text or send a message first"),
+                };
+                let mut clients: Vec<Box<dyn Client>> = vec![];
+                for model in &models {
+                    // Each model gets its own copy of the config, the context stays the same
+                    let mut config = self.config.read().clone();
+                    config.set_model(model)?;
+                    clients.push(init_client(Arc::new(RwLock::new(config)))?);
+                }
+                let replies = match send_message_concurrently(&clients, &input, self.abort.clone())?
+                {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let mut output = String::new();
+                for (model, reply) in models.iter().zip(replies) {
+                    let reply = reply
+                        .and_then(|v| self.config.read().filter_reply(&v))
+                        .unwrap_or_else(|err| format!("Error: {err:#}"));
+                    output.push_str(&format!("### {model}\n\n{}\n\n", reply.trim()));
+                }
+                let render_options = self.config.read().get_render_options()?;
+                let mut markdown_render = MarkdownRender::init(render_options)?;
+                print_now!("{}\n\n", markdown_render.render(output.trim_end()));
+            }
+            ReplCmd::Benchmark(models) => {
+                let mut output = String::from(
+                    "| model | first token | tokens | tok/s | total |\n|:---|---:|---:|---:|---:|\n",
+                );
+                for model in &models {
+                    print_now!("{}\n", format!("Benchmarking {model}…").dim());
+                    let row = match self.benchmark(model) {
+                        Ok((first_token, tokens, total)) => {
+                            let generating = (total - first_token).as_secs_f64();
+                            let speed = if generating > 0.0 {
+                                tokens as f64 / generating
+                            } else {
+                                0.0
+                            };
+                            format!(
+                                "| {model} | {:.2}s | {tokens} | {speed:.1} | {:.2}s |",
+                                first_token.as_secs_f64(),
+                                total.as_secs_f64()
+                            )
+                        }
+                        Err(err) => {
+                            let err = format!("{err:#}").replace('|', "\\|");
+                            format!("| {model} | Error: {err} |")
+                        }
+                    };
+                    if self.abort.aborted() {
+                        return Ok(());
+                    }
+                    output.push_str(&row);
+                    output.push('\n');
+                }
+                let render_options = self.config.read().get_render_options()?;
+                let mut markdown_render = MarkdownRender::init(render_options)?;
+                print_now!("\n{}\n\n", markdown_render.render(output.trim_end()));
+            }
+            ReplCmd::Diff => {
+                let (previous, last) = {
+                    let config = self.config.read();
+                    match (&config.previous_reply, &config.last_message) {
+                        (Some(previous), Some((_, last))) => (previous.clone(), last.clone()),
+                        _ => bail!("Need two replies to diff"),
+                    }
+                };
+                let output = diff_lines(&previous, &last)
+                    .into_iter()
+                    .map(|line| match line {
+                        DiffLine::Same(v) => format!("  {v}"),
+                        DiffLine::Removed(v) => format!("- {v}").red().to_string(),
+                        DiffLine::Added(v) => format!("+ {v}").green().to_string(),
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                print_now!("{}\n\n", output);
+            }
+            ReplCmd::ListMacros => {
+                let output = self.config.read().macros_info();
+                print_now!("{}\n\n", output);
+            }
+            ReplCmd::ListTemplates => {
+                let output = self.config.read().templates_info();
+                print_now!("{}\n\n", output);
+            }
+            ReplCmd::SaveTemplate(name, text) => {
+                let text = if text.is_empty() {
+                    let text = self.config.read().templates.get(&name).cloned();
+                    edit_text(&text.unwrap_or_default())?.trim_end().to_string()
+                } else {
+                    parse_template_text(&text)
+                };
+                self.config.write().save_template(&name, &text)?;
+                print_now!("\n");
+            }
+            ReplCmd::UseTemplate(name, input) => {
+                let prompt = self.config.read().apply_template(&name, &input)?;
+                self.handle(ReplCmd::Submit(prompt))?;
+            }
+            ReplCmd::SaveMacro(name) => {
+                let lines = self.config.read().macros.get(&name).cloned();
+                let text = lines.unwrap_or_default().join("\n");
+                let text = edit_text(&text)?;
+                let lines = text
+                    .lines()
+                    .map(|v| v.trim())
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.to_string())
+                    .collect();
+                self.config.write().save_macro(&name, lines)?;
+                print_now!("\n");
+            }
+            ReplCmd::Search(pattern) => {
+                let output = self.config.read().search_session(&pattern)?;
+                print_now!("{}\n\n", output);
+            }
+            ReplCmd::Vars(args) => {
+                let parts: Vec<&str> = args.splitn(3, char::is_whitespace).collect();
+                match parts.as_slice() {
+                    [""] => {
+                        let output = self.config.read().vars_info()?;
+                        print_now!("{}\n", output);
+                    }
+                    ["set", name, value] => {
+                        self.config
+                            .write()
+                            .set_session_variable(name, Some(value.trim()))?;
+                        print_now!("\n");
+                    }
+                    ["unset", name] => {
+                        self.config.write().set_session_variable(name, None)?;
+                        print_now!("\n");
+                    }
+                    _ => bail!("Usage: .vars [set <name> <value> | unset <name>]"),
+                }
+            }
+            ReplCmd::Compress(keep) => {
+                let before = {
+                    let config = self.config.read();
+                    if config.dry_run {
+                        bail!("Unable to compress in dry-run mode");
+                    }
+                    let session = match config.session.as_ref() {
+                        Some(v) => v,
+                        None => bail!("No session"),
+                    };
+                    if session.exchanges() <= keep {
+                        bail!("Nothing to compress");
+                    }
+                    session.tokens
+                };
+                let client = init_client(self.config.clone())?;
+                let summary = client.send_message(SUMMARIZE_PROMPT, self.abort.clone())?;
+                let after = self.config.write().compress_session(&summary, keep)?;
+                print_now!("Compressed the session from {before} to {after} tokens\n\n");
+            }
+            ReplCmd::Clear(context) => {
+                if context {
+                    self.config.write().clear_context()?;
+                }
+                execute!(stdout(), Clear(ClearType::All), MoveTo(0, 0))?;
+            }
+            ReplCmd::Pin(turn, pinned) => {
+                self.config.write().pin_message(turn, pinned)?;
+                print_now!("\n");
+            }
+            ReplCmd::Undo => {
+                self.config.write().undo_message()?;
+                print_now!("\n");
+            }
+            ReplCmd::SaveFile(args) => {
+                let mut force = false;
+                let mut file = None;
+                for arg in args.split_whitespace() {
+                    if arg == "--force" {
+                        force = true;
+                    } else {
+                        file = Some(arg);
+                    }
+                }
+                let file = match file {
+                    Some(v) => Path::new(v),
+                    None => bail!("Usage: .save <file name> [--force]"),
+                };
+                let reply = match self.config.read().last_message.as_ref() {
+                    Some((_, reply)) => reply.clone(),
+                    None => bail!("No message to save"),
+                };
+                if file.exists() && !force {
+                    bail!(
+                        "File '{}' already exists, use --force to overwrite",
+                        file.display()
+                    );
+                }
+                if let Some(parent) = file.parent() {
+                    if !parent.as_os_str().is_empty() && !parent.exists() {
+                        create_dir_all(parent).with_context(|| {
+                            format!("Failed to create directory '{}'", parent.display())
+                        })?;
+                    }
+                }
+                fs::write(file, reply)
+                    .with_context(|| format!("Failed to write to '{}'", file.display()))?;
+                print_now!("\n");
+            }
         }
         Ok(())
     }
 
+    /// The lines of a macro, to be run one after another
+    pub fn macro_lines(&self, name: &str) -> Result<Vec<String>> {
+        self.config.read().get_macro(name)
+    }
+
+    /// Send the last input again, replacing its exchange in the session
+    fn resubmit(&self) -> Result<()> {
+        let input = match self.config.read().last_message.as_ref() {
+            Some((input, _)) => input.clone(),
+            None => bail!("Nothing to retry"),
+        };
+        let discarded = self.config.write().discard_last_message();
+        let ret = self.handle(ReplCmd::Submit(input));
+        if ret.is_err() {
+            if let Some(messages) = discarded {
+                self.config.write().restore_messages(messages);
+            }
+        }
+        ret
+    }
+
+    /// List the models of the current client with their max tokens,
+    /// only the statically configured ones if it has no models endpoint
+    fn list_models(&self) -> Result<ModelList> {
+        let (client_name, configured) = {
+            let config = self.config.read();
+            let client_name = config.model_info.client.clone();
+            let configured: Vec<_> = list_models(&config)
+                .into_iter()
+                .filter(|v| v.client == client_name)
+                .collect();
+            (client_name, configured)
+        };
+        if let Some(models) = self.models.borrow().get(&client_name) {
+            return Ok(models.clone());
+        }
+        let client = init_client(self.config.clone())?;
+        let models: Vec<_> = match client.fetch_models()? {
+            Some(ids) => ids
+                .into_iter()
+                .map(|id| {
+                    let max_tokens = configured
+                        .iter()
+                        .find(|v| v.name == id)
+                        .map(|v| v.max_tokens);
+                    (format!("{client_name}:{id}"), max_tokens)
+                })
+                .collect(),
+            None => configured
+                .iter()
+                .map(|v| (v.stringify(), Some(v.max_tokens)))
+                .collect(),
+        };
+        self.models.borrow_mut().insert(client_name, models.clone());
+        Ok(models)
+    }
+
     fn copy(&self, text: &str) -> Result<()> {
         match self.clipboard.as_ref() {
-            Err(err) => bail!("{}", err),
+            Err(err) => {
+                if !self.config.read().osc52_copy {
+                    bail!("{err}. Set `osc52_copy: true` to copy through the terminal instead")
+                }
+                if !stdout().is_terminal() {
+                    bail!("{err}. Unable to copy through the terminal, stdout is not a tty")
+                }
+                print_now!("{}", osc52_sequence(text));
+                Ok(())
+            }
             Ok(clip) => {
                 clip.borrow_mut().set_text(text)?;
                 Ok(())
             }
         }
     }
+
+    /// Stream the benchmark prompt from the model without context or cache,
+    /// returning the time to the first token, the reply tokens and the total time
+    fn benchmark(&self, model: &str) -> Result<(Duration, usize, Duration)> {
+        let mut config = self.config.read().clone();
+        config.set_model(model)?;
+        config.role = None;
+        config.session = None;
+        config.cache_ttl = 0;
+        let client = init_client(Arc::new(RwLock::new(config)))?;
+        // Kept until the reply ends, nothing is printed
+        let (tx, _rx) = unbounded();
+        let mut handler =
+            ReplyStreamHandler::new(tx, self.abort.clone(), false, false, Duration::ZERO);
+        let started_at = Instant::now();
+        client.send_message_streaming(BENCHMARK_PROMPT, &mut handler)?;
+        let total = started_at.elapsed();
+        let first_token = match handler.get_started_at() {
+            Some(v) => v - started_at,
+            None => bail!("No reply"),
+        };
+        Ok((first_token, count_tokens(handler.get_buffer()), total))
+    }
+
+    fn paste(&self) -> Result<String> {
+        match self.clipboard.as_ref() {
+            Err(err) => bail!("{err}"),
+            Ok(clip) => Ok(clip.borrow_mut().get_text()?),
+        }
+    }
 }
 
 pub struct ReplyStreamHandler {
     sender: Sender<ReplyStreamEvent>,
     buffer: String,
     abort: SharedAbortSignal,
+    show_stats: bool,
+    show_reading_time: bool,
+    started_at: Option<Instant>,
+    /// Set once the first token arrives, watched by the request timeout
+    received: Arc<AtomicBool>,
+    /// Chunks are held and sent together at most this often, zero sends each one
+    flush_interval: Duration,
+    pending: Arc<Mutex<PendingText>>,
+    timer_started: bool,
+}
+
+/// Text held back by the handler, shared with the thread that sends it on a timer
+#[derive(Default)]
+struct PendingText {
+    text: String,
+    done: bool,
 }
 
 impl ReplyStreamHandler {
-    pub fn new(sender: Sender<ReplyStreamEvent>, abort: SharedAbortSignal) -> Self {
+    pub fn new(
+        sender: Sender<ReplyStreamEvent>,
+        abort: SharedAbortSignal,
+        show_stats: bool,
+        show_reading_time: bool,
+        flush_interval: Duration,
+    ) -> Self {
         Self {
             sender,
             abort,
             buffer: String::new(),
+            show_stats,
+            show_reading_time,
+            started_at: None,
+            received: Arc::new(AtomicBool::new(false)),
+            flush_interval,
+            pending: Arc::new(Mutex::new(PendingText::default())),
+            timer_started: false,
         }
     }
 
@@ -165,16 +856,77 @@ impl ReplyStreamHandler {
         if self.buffer.is_empty() && text == "\n\n" {
             return Ok(());
         }
+        if self.started_at.is_none() {
+            self.started_at = Some(Instant::now());
+            self.received.store(true, Ordering::SeqCst);
+        }
         self.buffer.push_str(text);
+        self.pending.lock().text.push_str(text);
+        // Held chunks are sent by the timer, a newline sends them at once
+        if self.flush_interval.is_zero() || text.contains('\n') {
+            self.flush()?;
+        } else if !self.timer_started {
+            self.start_timer();
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut pending = self.pending.lock();
+        if pending.text.is_empty() {
+            return Ok(());
+        }
+        let text = std::mem::take(&mut pending.text);
         let ret = self
             .sender
-            .send(ReplyStreamEvent::Text(text.to_string()))
+            .send(ReplyStreamEvent::Text(text))
             .with_context(|| "Failed to send StreamEvent:Text");
         self.safe_ret(ret)?;
         Ok(())
     }
 
+    /// Send the held text every `flush_interval` until the reply is done
+    fn start_timer(&mut self) {
+        self.timer_started = true;
+        let pending = self.pending.clone();
+        let sender = self.sender.clone();
+        let interval = self.flush_interval;
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let mut pending = pending.lock();
+            if pending.done {
+                break;
+            }
+            if !pending.text.is_empty() {
+                let text = std::mem::take(&mut pending.text);
+                if sender.send(ReplyStreamEvent::Text(text)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
     pub fn done(&mut self) -> Result<()> {
+        self.flush()?;
+        self.pending.lock().done = true;
+        if self.show_stats {
+            if let Some(stats) = self.stats() {
+                let ret = self
+                    .sender
+                    .send(ReplyStreamEvent::Footer(stats.dim().to_string()))
+                    .with_context(|| "Failed to send StreamEvent:Footer");
+                self.safe_ret(ret)?;
+            }
+        }
+        if self.show_reading_time {
+            if let Some(reading_time) = self.reading_time() {
+                let ret = self
+                    .sender
+                    .send(ReplyStreamEvent::Footer(reading_time.dim().to_string()))
+                    .with_context(|| "Failed to send StreamEvent:Footer");
+                self.safe_ret(ret)?;
+            }
+        }
         let ret = self
             .sender
             .send(ReplyStreamEvent::Done)
@@ -187,10 +939,42 @@ impl ReplyStreamHandler {
         &self.buffer
     }
 
+    /// When the first token arrived
+    pub fn get_started_at(&self) -> Option<Instant> {
+        self.started_at
+    }
+
     pub fn get_abort(&self) -> SharedAbortSignal {
         self.abort.clone()
     }
 
+    pub fn get_received(&self) -> Arc<AtomicBool> {
+        self.received.clone()
+    }
+
+    fn stats(&self) -> Option<String> {
+        let elapsed = self.started_at?.elapsed().as_secs_f64();
+        let tokens = count_tokens(&self.buffer);
+        let speed = if elapsed > 0.0 {
+            tokens as f64 / elapsed
+        } else {
+            0.0
+        };
+        Some(format!(
+            "· {tokens} tokens · {speed:.1} tok/s · {elapsed:.1}s"
+        ))
+    }
+
+    /// The word count and reading time of a long reply
+    fn reading_time(&self) -> Option<String> {
+        let words = count_words(&self.buffer);
+        if words < READING_TIME_MIN_WORDS {
+            return None;
+        }
+        let minutes = words.div_ceil(WORDS_PER_MINUTE);
+        Some(format!("· {words} words · ~{minutes} min read"))
+    }
+
     fn safe_ret(&self, ret: Result<()>) -> Result<()> {
         if ret.is_err() && self.abort.aborted() {
             return Ok(());
@@ -199,7 +983,15 @@ impl ReplyStreamHandler {
     }
 }
 
+impl Drop for ReplyStreamHandler {
+    fn drop(&mut self) {
+        self.pending.lock().done = true;
+    }
+}
+
 pub enum ReplyStreamEvent {
     Text(String),
+    /// Printed on its own line once the reply is finished
+    Footer(String),
     Done,
 }
@@ -1,18 +1,21 @@
 use super::{
-    highlighter::ReplHighlighter, prompt::ReplPrompt, validator::ReplValidator, REPL_COMMANDS,
+    completer::ReplCompleter, highlighter::ReplHighlighter, prompt::ReplPrompt, REPL_COMMANDS,
 };
 
-use crate::config::SharedConfig;
+use crate::config::{Config, SharedConfig};
 
 use anyhow::Result;
 use reedline::{
     default_emacs_keybindings, default_vi_insert_keybindings, default_vi_normal_keybindings,
-    ColumnarMenu, DefaultCompleter, EditMode, Emacs, KeyCode, KeyModifiers, Keybindings, Reedline,
+    ColumnarMenu, EditMode, Emacs, FileBackedHistory, KeyCode, KeyModifiers, Keybindings, Reedline,
     ReedlineEvent, ReedlineMenu, Vi,
 };
 
 const MENU_NAME: &str = "completion_menu";
 
+/// `.set` may carry secrets, so it is never written to the history file
+const HISTORY_EXCLUSION_PREFIX: &str = ".set ";
+
 pub struct Repl {
     pub(crate) editor: Reedline,
     pub(crate) prompt: ReplPrompt,
@@ -25,7 +28,7 @@ impl Repl {
             .map(|(v, _)| v.to_string())
             .collect();
 
-        let completer = Self::create_completer(&config, &commands);
+        let completer = ReplCompleter::new(config.clone());
         let highlighter = ReplHighlighter::new(commands, config.clone());
         let menu = Self::create_menu();
         let edit_mode: Box<dyn EditMode> = if config.read().keybindings.is_vi() {
@@ -46,22 +49,18 @@ impl Repl {
             .with_edit_mode(edit_mode)
             .with_quick_completions(true)
             .with_partial_completions(true)
-            .with_validator(Box::new(ReplValidator))
             .with_ansi_colors(true);
-        editor.enable_bracketed_paste()?;
+        let max_history = config.read().max_history;
+        if max_history > 0 {
+            let history = FileBackedHistory::with_file(max_history, Config::history_file()?)?;
+            editor = editor
+                .with_history(Box::new(history))
+                .with_history_exclusion_prefix(Some(HISTORY_EXCLUSION_PREFIX.into()));
+        }
         let prompt = ReplPrompt::new(config);
         Ok(Self { editor, prompt })
     }
 
-    fn create_completer(config: &SharedConfig, commands: &[String]) -> DefaultCompleter {
-        let mut completion = commands.to_vec();
-        completion.extend(config.read().repl_completions());
-        let mut completer =
-            DefaultCompleter::with_inclusions(&['.', '-', '_', ':']).set_min_word_len(2);
-        completer.insert(completion.clone());
-        completer
-    }
-
     fn extra_keybindings(keybindings: &mut Keybindings) {
         keybindings.add_binding(
             KeyModifiers::NONE,
@@ -72,9 +71,9 @@ impl Repl {
             ]),
         );
         keybindings.add_binding(
-            KeyModifiers::CONTROL,
-            KeyCode::Char('s'),
-            ReedlineEvent::Submit,
+            KeyModifiers::ALT,
+            KeyCode::Char('m'),
+            ReedlineEvent::ExecuteHostCommand(".model next".into()),
         );
     }
 
@@ -1,43 +1,135 @@
 mod abort;
+mod completer;
 mod handler;
 mod highlighter;
 mod init;
 mod prompt;
-mod validator;
 
 pub use self::abort::*;
 pub use self::handler::*;
 pub use self::init::Repl;
 
-use crate::config::SharedConfig;
+use self::prompt::BlockPrompt;
+
+use crate::config::{Config, SharedConfig};
 use crate::print_now;
+use crate::utils::image_mime;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
 use fancy_regex::Regex;
+use inquire::Confirm;
 use lazy_static::lazy_static;
 use reedline::Signal;
+use std::fs::remove_file;
 use std::rc::Rc;
 
-pub const REPL_COMMANDS: [(&str, &str); 14] = [
+pub const REPL_COMMANDS: [(&str, &str); 56] = [
     (".help", "Print this help message"),
     (".info", "Print system info"),
-    (".edit", "Multi-line editing (CTRL+S to finish)"),
-    (".model", "Switch LLM model"),
+    (".tokens", "Show token usage of the current context"),
+    (".cost", "Estimate the spend of the current session"),
+    (
+        ".count",
+        "Count the tokens of a text or @file without sending it",
+    ),
+    (".edit", "Compose the prompt in $EDITOR"),
+    (
+        ".submit",
+        "Type lines until <<TERMINATOR, or EOF with -, and submit them",
+    ),
+    (".retry", "Resend the last prompt"),
+    (
+        ".regen",
+        "Resend the last prompt with a higher or given temperature",
+    ),
+    (".undo", "Remove the last exchange from the session"),
+    (".diff", "Show the changes between the last two replies"),
+    (".compare", "Send the same prompt to several models at once"),
+    (".benchmark", "Compare the latency and speed of models"),
+    (".compress", "Summarize the older messages of the session"),
+    (".vars", "List or set the variables of the session"),
+    (".search", "Search the messages of the session"),
+    (
+        ".pin",
+        "Keep a turn word for word when the session is compressed",
+    ),
+    (".unpin", "Let a pinned turn be summarized again"),
+    (
+        ".model",
+        "Switch LLM model, list available models if no name given",
+    ),
     (".role", "Use role"),
+    (".role save", "Save the current role under a new name"),
+    (".role edit", "Edit a role in $EDITOR"),
+    (".role clone", "Copy a role under a new name"),
+    (
+        ".role default",
+        "Show or set the role used on startup, '--unset' to clear it",
+    ),
     (".info role", "Show role info"),
-    (".exit role", "Leave current role"),
+    (".prompt", "Show the system prompt in effect"),
+    (
+        ".context show",
+        "Print the messages the next request carries, with their roles",
+    ),
+    (
+        ".exit role",
+        "Leave current role, back to the default role if any",
+    ),
     (".session", "Start a context-aware chat session"),
+    (".session list", "List the saved sessions, the latest first"),
+    (".session export", "Export a session as JSON"),
+    (".session import", "Import a session exported as JSON"),
+    (
+        ".session merge",
+        "Append the messages of a saved session to the current one",
+    ),
+    (
+        ".session lock",
+        "Keep the session on its model, 'unlock' to undo",
+    ),
     (".info session", "Show session info"),
+    (".replay", "Print a saved session without starting it"),
+    (".title", "Rename the current session"),
+    (".fork", "Continue in a copy of the current session"),
     (".exit session", "End the current session"),
+    (
+        ".clear",
+        "Clear the screen, and the session messages with 'context'",
+    ),
+    (
+        ".history clear",
+        "Clear the input history, '--force' to skip the prompt",
+    ),
     (".set", "Modify the configuration parameters"),
+    (".macro", "List the macros"),
+    (".macro run", "Run the REPL commands of a macro"),
+    (
+        ".macro save",
+        "Write a macro in $EDITOR, one command per line",
+    ),
+    (".template", "List the prompt templates"),
+    (
+        ".template save",
+        "Save a prompt template, in $EDITOR if no text is given",
+    ),
+    (
+        ".template use",
+        "Send a template with the text as {{input}}",
+    ),
+    (".last", "Print the last reply again"),
     (".copy", "Copy the last reply to the clipboard"),
-    (".read", "Import from file and submit"),
+    (".copy-block", "Copy a code block of the last reply"),
+    (".paste", "Submit the clipboard contents"),
+    (".save", "Save the last reply to a file"),
+    (".file", "Import files and submit"),
+    (".image", "Submit images along with a prompt"),
     (".exit", "Exit the REPL"),
 ];
 
 lazy_static! {
     static ref COMMAND_RE: Regex = Regex::new(r"^\s*(\.\S+)\s*").unwrap();
-    static ref EDIT_RE: Regex = Regex::new(r"^\s*\.edit\s*").unwrap();
 }
 
 impl Repl {
@@ -55,6 +147,9 @@ impl Repl {
             if abort.aborted_ctrlc() && !already_ctrlc {
                 already_ctrlc = true;
             }
+            // $EDITOR and $PAGER may turn bracketed paste off when they exit,
+            // without it a pasted block is submitted at its first newline
+            self.editor.enable_bracketed_paste()?;
             let sig = self.editor.read_line(&self.prompt);
             match sig {
                 Ok(Signal::Success(line)) => {
@@ -105,32 +200,197 @@ impl Repl {
                         handler.handle(ReplCmd::Info)?;
                     }
                 },
+                ".prompt" => {
+                    handler.handle(ReplCmd::PromptInfo)?;
+                }
+                ".context" => match args {
+                    None | Some("show") => handler.handle(ReplCmd::ShowContext)?,
+                    Some(_) => unknown_command(),
+                },
+                ".tokens" => {
+                    handler.handle(ReplCmd::TokenUsage)?;
+                }
+                ".count" => match args {
+                    Some(text) => handler.handle(ReplCmd::Count(text.to_string()))?,
+                    None => print_now!("Usage: .count <text>|@<file name>[:start-end]\n\n"),
+                },
+                ".cost" => {
+                    handler.handle(ReplCmd::Cost)?;
+                }
                 ".edit" => {
-                    if let Some(text) = args {
-                        handler.handle(ReplCmd::Submit(text.to_string()))?;
+                    handler.handle(ReplCmd::Edit(args.map(|v| v.to_string())))?;
+                }
+                ".submit" => match args.and_then(parse_block_terminator) {
+                    Some(terminator) => {
+                        if let Some(text) = self.read_block(terminator)? {
+                            handler.handle(ReplCmd::Submit(text))?;
+                        }
                     }
+                    None => print_now!("Usage: .submit <<<terminator>|-\n\n"),
+                },
+                ".retry" => {
+                    handler.handle(ReplCmd::Retry)?;
                 }
-                ".model" => match args {
-                    Some(name) => handler.handle(ReplCmd::SetModel(name.to_string()))?,
-                    None => print_now!("Usage: .model <name>\n\n"),
+                ".regen" => match args.map(|v| v.parse::<f64>()) {
+                    None => handler.handle(ReplCmd::Regenerate(None))?,
+                    Some(Ok(value)) => handler.handle(ReplCmd::Regenerate(Some(value)))?,
+                    Some(Err(_)) => print_now!("Usage: .regen [<temperature>]\n\n"),
                 },
-                ".role" => match args {
-                    Some(name) => handler.handle(ReplCmd::SetRole(name.to_string()))?,
-                    None => print_now!("Usage: .role <name>\n\n"),
+                ".compress" => match args.map(|v| v.parse::<usize>()) {
+                    None => handler.handle(ReplCmd::Compress(2))?,
+                    Some(Ok(keep)) => handler.handle(ReplCmd::Compress(keep))?,
+                    Some(Err(_)) => print_now!("Usage: .compress [<turns to keep>]\n\n"),
                 },
-                ".session" => {
-                    handler.handle(ReplCmd::StartSession(args.map(|v| v.to_string())))?;
+                ".vars" => {
+                    handler.handle(ReplCmd::Vars(args.unwrap_or_default().to_string()))?;
                 }
+                ".search" => match args {
+                    Some(pattern) => handler.handle(ReplCmd::Search(pattern.to_string()))?,
+                    None => print_now!("Usage: .search <text or regex>\n\n"),
+                },
+                ".pin" | ".unpin" => match args.map(|v| v.parse::<usize>()) {
+                    Some(Ok(turn)) => handler.handle(ReplCmd::Pin(turn, cmd == ".pin"))?,
+                    _ => print_now!("Usage: {cmd} <turn>\n\n"),
+                },
+                ".undo" => {
+                    handler.handle(ReplCmd::Undo)?;
+                }
+                ".diff" => {
+                    handler.handle(ReplCmd::Diff)?;
+                }
+                ".compare" => match args {
+                    Some(args) => {
+                        let (models, text) = match args.split_once(char::is_whitespace) {
+                            Some((models, text)) => (models, Some(text.trim().to_string())),
+                            None => (args, None),
+                        };
+                        let models = models
+                            .split(',')
+                            .filter(|v| !v.is_empty())
+                            .map(|v| v.to_string())
+                            .collect();
+                        handler.handle(ReplCmd::Compare(models, text))?;
+                    }
+                    None => print_now!("Usage: .compare <model>,<model>... [text]\n\n"),
+                },
+                ".benchmark" => match args {
+                    Some(args) => {
+                        let models = args
+                            .split(|c: char| c == ',' || c.is_whitespace())
+                            .filter(|v| !v.is_empty())
+                            .map(|v| v.to_string())
+                            .collect();
+                        handler.handle(ReplCmd::Benchmark(models))?;
+                    }
+                    None => print_now!("Usage: .benchmark <model>,<model>...\n\n"),
+                },
+                ".model" => match args {
+                    None | Some("list") => handler.handle(ReplCmd::ListModels)?,
+                    Some("next") => handler.handle(ReplCmd::CycleModel(true))?,
+                    Some("prev") => handler.handle(ReplCmd::CycleModel(false))?,
+                    Some(args) => match args.strip_suffix("--force") {
+                        Some(name) => {
+                            handler.handle(ReplCmd::SetModel(name.trim().to_string(), true))?
+                        }
+                        None => handler.handle(ReplCmd::SetModel(args.to_string(), false))?,
+                    },
+                },
+                ".role" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    Some(("save", args)) if !args.is_empty() => {
+                        handler.handle(ReplCmd::SaveRole(args.to_string()))?
+                    }
+                    Some(("edit", name)) if !name.is_empty() => {
+                        handler.handle(ReplCmd::EditRole(name.trim().to_string()))?
+                    }
+                    Some(("clone", args)) => {
+                        match args.split_whitespace().collect::<Vec<_>>()[..] {
+                            [base, name] => handler
+                                .handle(ReplCmd::CloneRole(base.to_string(), name.to_string()))?,
+                            _ => print_now!("Usage: .role clone <role> <new name>\n\n"),
+                        }
+                    }
+                    Some(("default", name)) => {
+                        handler.handle(ReplCmd::DefaultRole(name.trim().to_string()))?
+                    }
+                    _ => match args.and_then(parse_role_args) {
+                        Some((name, variables)) => {
+                            handler.handle(ReplCmd::SetRole(name.to_string(), variables))?
+                        }
+                        None => print_now!("Usage: .role <name> [<variable>=<value>]...\n\n"),
+                    },
+                },
+                ".session" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    Some(("export", args)) => {
+                        handler.handle(ReplCmd::ExportSession(args.to_string()))?
+                    }
+                    Some(("import", args)) => {
+                        handler.handle(ReplCmd::ImportSession(args.to_string()))?
+                    }
+                    Some(("list", "")) => handler.handle(ReplCmd::ListSessions)?,
+                    Some(("merge", name)) if !name.is_empty() => {
+                        handler.handle(ReplCmd::MergeSession(name.trim().to_string()))?
+                    }
+                    Some(("lock", "")) => handler.handle(ReplCmd::LockSession(true))?,
+                    Some(("unlock", "")) => handler.handle(ReplCmd::LockSession(false))?,
+                    _ => handler.handle(ReplCmd::StartSession(args.map(|v| v.to_string())))?,
+                },
+                ".replay" => match args {
+                    Some(name) => handler.handle(ReplCmd::Replay(name.to_string()))?,
+                    None => print_now!("Usage: .replay <name>\n\n"),
+                },
+                ".fork" => match args {
+                    Some(name) => handler.handle(ReplCmd::Fork(name.to_string()))?,
+                    None => print_now!("Usage: .fork <name>\n\n"),
+                },
+                ".title" => match args {
+                    Some(name) => handler.handle(ReplCmd::RenameSession(name.to_string()))?,
+                    None => print_now!("Usage: .title <name>\n\n"),
+                },
                 ".set" => {
                     handler.handle(ReplCmd::Set(args.unwrap_or_default().to_string()))?;
                 }
+                ".last" => {
+                    handler.handle(ReplCmd::ShowLast)?;
+                }
                 ".copy" => {
                     handler.handle(ReplCmd::Copy)?;
                 }
-                ".read" => match args {
-                    Some(file) => handler.handle(ReplCmd::ReadFile(file.to_string()))?,
-                    None => print_now!("Usage: .read <file name>\n\n"),
+                ".paste" => {
+                    handler.handle(ReplCmd::Paste)?;
+                }
+                ".copy-block" => match args.map(|v| v.parse::<usize>()) {
+                    Some(Ok(index)) => handler.handle(ReplCmd::CopyBlock(Some(index)))?,
+                    Some(Err(_)) => print_now!("Usage: .copy-block [index]\n\n"),
+                    None => handler.handle(ReplCmd::CopyBlock(None))?,
+                },
+                ".save" => match args {
+                    Some(args) => handler.handle(ReplCmd::SaveFile(args.to_string()))?,
+                    None => print_now!("Usage: .save <file name> [--force]\n\n"),
                 },
+                ".file" => match args {
+                    Some(args) => {
+                        let files = args.split_whitespace().map(|v| v.to_string()).collect();
+                        handler.handle(ReplCmd::ReadFile(files))?;
+                    }
+                    None => print_now!("Usage: .file <file name>[:start-end]...\n\n"),
+                },
+                ".image" => {
+                    // Leading words that are images are the paths, the rest is the text
+                    let mut input = args.unwrap_or_default();
+                    let mut paths = vec![];
+                    while let Some((path, rest)) = input.split_once(char::is_whitespace) {
+                        if image_mime(path).is_none() {
+                            break;
+                        }
+                        paths.push(path.to_string());
+                        input = rest.trim_start();
+                    }
+                    if paths.is_empty() || input.is_empty() {
+                        print_now!("Usage: .image <image>... <text>\n\n");
+                    } else {
+                        handler.handle(ReplCmd::SubmitWithImage(input.to_string(), paths))?;
+                    }
+                }
                 ".exit" => match args {
                     Some("role") => handler.handle(ReplCmd::ExitRole)?,
                     Some("session") => handler.handle(ReplCmd::ExitSession)?,
@@ -141,6 +401,8 @@ impl Repl {
                 },
                 // deprecated
                 ".clear" => match args {
+                    None => handler.handle(ReplCmd::Clear(false))?,
+                    Some("context") => handler.handle(ReplCmd::Clear(true))?,
                     Some("role") => {
                         print_now!("Deprecated. Use '.exit role' instead.\n\n");
                     }
@@ -149,6 +411,44 @@ impl Repl {
                     }
                     _ => unknown_command(),
                 },
+                ".history" => match args {
+                    Some("clear") => self.clear_history(false)?,
+                    Some("clear --force") => self.clear_history(true)?,
+                    _ => print_now!("Usage: .history clear [--force]\n\n"),
+                },
+                ".macro" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    None => handler.handle(ReplCmd::ListMacros)?,
+                    Some(("run", name)) if !name.is_empty() => {
+                        for line in handler.macro_lines(name.trim())? {
+                            print_now!("{}\n", format!("〉{line}").dim());
+                            if self.handle_line(handler, &line)? {
+                                return Ok(true);
+                            }
+                        }
+                    }
+                    Some(("save", name)) if !name.is_empty() => {
+                        handler.handle(ReplCmd::SaveMacro(name.trim().to_string()))?
+                    }
+                    _ => print_now!("Usage: .macro [run <name> | save <name>]\n\n"),
+                },
+                ".template" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    None | Some(("list", "")) => handler.handle(ReplCmd::ListTemplates)?,
+                    Some((action @ ("save" | "use"), args)) if !args.trim().is_empty() => {
+                        let (name, text) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+                        let (name, text) = (name.to_string(), text.trim().to_string());
+                        if action == "save" {
+                            handler.handle(ReplCmd::SaveTemplate(name, text))?
+                        } else {
+                            handler.handle(ReplCmd::UseTemplate(name, text))?
+                        }
+                    }
+                    _ => print_now!(
+                        "Usage: .template [list | save <name> [<text>] | use <name> <text>]\n\n"
+                    ),
+                },
+                ".read" => {
+                    print_now!("Deprecated. Use '.file' instead.\n\n");
+                }
                 _ => unknown_command(),
             },
             None => {
@@ -158,6 +458,45 @@ impl Repl {
 
         Ok(false)
     }
+
+    /// Read lines until the terminator, `None` when the block is empty or canceled
+    fn read_block(&mut self, terminator: &str) -> Result<Option<String>> {
+        let mut lines = vec![];
+        loop {
+            match self.editor.read_line(&BlockPrompt)? {
+                Signal::Success(line) if line.trim_end() == terminator => break,
+                Signal::Success(line) => lines.push(line),
+                _ => {
+                    print_now!("\n");
+                    return Ok(None);
+                }
+            }
+        }
+        let text = lines.join("\n");
+        if text.trim().is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(text))
+    }
+
+    /// Forget the inputs kept in memory and delete the history file
+    fn clear_history(&mut self, force: bool) -> Result<()> {
+        if !force
+            && !Confirm::new("Clear the input history?")
+                .with_default(false)
+                .prompt()?
+        {
+            return Ok(());
+        }
+        // The entries are cleared even if it fails, as it does without a history file yet
+        let _ = self.editor.history_mut().clear();
+        let path = Config::history_file()?;
+        if path.exists() {
+            remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        print_now!("\n");
+        Ok(())
+    }
 }
 
 fn unknown_command() {
@@ -173,7 +512,7 @@ fn dump_repl_help() {
     print_now!(
         r###"{head}
 
-Press Ctrl+C to abort readline, Ctrl+D to exit the REPL
+Press Ctrl+C to abort readline, Ctrl+D to exit the REPL, Esc to stop the reply, Alt+M to switch to the next recent model
 
 "###,
     );
@@ -190,6 +529,34 @@ fn parse_command(line: &str) -> Option<(&str, Option<&str>)> {
     }
     None
 }
+
+/// The terminator of `.submit <<END`, `-` stands for `<<EOF`
+fn parse_block_terminator(args: &str) -> Option<&str> {
+    if args == "-" {
+        return Some("EOF");
+    }
+    let terminator = args.strip_prefix("<<")?.trim();
+    if terminator.is_empty() || terminator.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(terminator)
+}
+
+/// Split `.role` args into the role name and its `<variable>=<value>` pairs
+pub fn parse_role_args(args: &str) -> Option<(&str, Vec<(String, String)>)> {
+    let mut parts = args.split_whitespace();
+    let name = parts.next()?;
+    let mut variables = vec![];
+    for part in parts {
+        let (key, value) = part.split_once('=')?;
+        if key.is_empty() {
+            return None;
+        }
+        variables.push((key.to_string(), value.to_string()));
+    }
+    Some((name, variables))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -215,4 +582,29 @@ mod tests {
             Some((".edit", Some("abc")))
         );
     }
+
+    #[test]
+    fn test_parse_block_terminator() {
+        assert_eq!(parse_block_terminator("<<END"), Some("END"));
+        assert_eq!(parse_block_terminator("<< END"), Some("END"));
+        assert_eq!(parse_block_terminator("-"), Some("EOF"));
+        assert_eq!(parse_block_terminator("<<"), None);
+        assert_eq!(parse_block_terminator("END"), None);
+    }
+
+    #[test]
+    fn test_parse_role_args() {
+        assert_eq!(parse_role_args("shell"), Some(("shell", vec![])));
+        assert_eq!(
+            parse_role_args("translate lang=French tone=formal"),
+            Some((
+                "translate",
+                vec![
+                    ("lang".into(), "French".into()),
+                    ("tone".into(), "formal".into())
+                ]
+            ))
+        );
+        assert_eq!(parse_role_args("translate French"), None);
+    }
 }
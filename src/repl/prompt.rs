@@ -20,8 +20,66 @@ impl ReplPrompt {
     }
 }
 
+/// Shown for each line of a `.submit` block
+#[derive(Clone)]
+pub struct BlockPrompt;
+
+impl Prompt for BlockPrompt {
+    fn render_prompt_left(&self) -> Cow<'_, str> {
+        Cow::Borrowed("")
+    }
+
+    fn render_prompt_right(&self) -> Cow<'_, str> {
+        Cow::Borrowed("")
+    }
+
+    fn render_prompt_indicator(&self, _prompt_mode: reedline::PromptEditMode) -> Cow<'_, str> {
+        Cow::Borrowed("… ")
+    }
+
+    fn render_prompt_multiline_indicator(&self) -> Cow<'_, str> {
+        Cow::Borrowed("")
+    }
+
+    fn render_prompt_history_search_indicator(
+        &self,
+        history_search: PromptHistorySearch,
+    ) -> Cow<'_, str> {
+        Cow::Owned(format!("(reverse-search: {}) ", history_search.term))
+    }
+}
+
+/// Fill the `{name}` placeholders of the format, unknown ones are kept as they are
+fn format_prompt(format: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(format.to_string(), |output, (name, value)| {
+            output.replace(&format!("{{{name}}}"), value)
+        })
+}
+
 impl Prompt for ReplPrompt {
     fn render_prompt_left(&self) -> Cow<str> {
+        let config = self.config.read();
+        if let Some(format) = &config.prompt_format {
+            let session = config.session.as_ref();
+            let role = session
+                .and_then(|v| v.role.as_ref())
+                .or(config.role.as_ref())
+                .map(|v| v.name.as_str())
+                .unwrap_or_default();
+            let values = [
+                ("role", role),
+                ("model", config.model_info.name.as_str()),
+                ("client", config.model_info.client.as_str()),
+                (
+                    "session",
+                    session.map(|v| v.name.as_str()).unwrap_or_default(),
+                ),
+            ];
+            return Cow::Owned(format_prompt(format, &values));
+        }
+        drop(config);
         if let Some(session) = &self.config.read().session {
             Cow::Owned(session.name.clone())
         } else if let Some(role) = &self.config.read().role {
@@ -83,3 +141,18 @@ impl Prompt for ReplPrompt {
         PROMPT_RIGHT_COLOR
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_prompt() {
+        let values = [("role", "coding"), ("model", "gpt-4o")];
+        assert_eq!(
+            format_prompt("[{role}:{model}]", &values),
+            "[coding:gpt-4o]"
+        );
+        assert_eq!(format_prompt("{other}", &values), "{other}");
+    }
+}
@@ -0,0 +1,333 @@
+use crate::client::{init_client, list_models};
+use crate::config::{Message, MessageRole, SharedConfig};
+use crate::repl::{AbortSignal, ReplyStreamEvent, ReplyStreamHandler};
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use crossbeam::channel::unbounded;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::spawn;
+use std::time::Duration;
+
+/// Requests with a larger body are rejected
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionsRequest {
+    model: Option<String>,
+    messages: Vec<Message>,
+    #[serde(default)]
+    stream: bool,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<usize>,
+    /// A string or an array of strings
+    stop: Option<Value>,
+}
+
+/// The fields shared by a completion and its chunks
+#[derive(Clone)]
+struct Completion {
+    id: String,
+    model: String,
+    created: i64,
+}
+
+impl Completion {
+    fn new(model: &str) -> Self {
+        let now = Utc::now();
+        Self {
+            id: format!("chatcmpl-{}", now.timestamp_millis()),
+            model: model.to_string(),
+            created: now.timestamp(),
+        }
+    }
+
+    fn message(&self, content: &str) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": "stop",
+            }],
+        })
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "delta": delta,
+                "finish_reason": finish_reason,
+            }],
+        })
+    }
+}
+
+/// Serve an OpenAI-compatible API that sends every request through the configured client
+pub fn run(config: SharedConfig, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("Failed to bind {addr}"))?;
+    eprintln!(
+        "Serving http://{addr}/v1/chat/completions with {}",
+        config.read().model_info.stringify()
+    );
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let config = config.clone();
+        spawn(move || {
+            if let Err(err) = handle_connection(stream, &config) {
+                eprintln!("Error: {err:?}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, config: &SharedConfig) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let (method, path, body) = match read_request(&mut reader) {
+        Ok(v) => v,
+        Err(err) => return write_error(&mut writer, 400, &format!("{err:#}")),
+    };
+    let path = path.split('?').next().unwrap_or_default();
+    match (method.as_str(), path) {
+        ("GET", "/v1/models") => {
+            let models: Vec<Value> = list_models(&config.read())
+                .iter()
+                .map(|v| json!({ "id": v.stringify(), "object": "model", "owned_by": v.client }))
+                .collect();
+            write_json(
+                &mut writer,
+                200,
+                &json!({ "object": "list", "data": models }),
+            )
+        }
+        ("POST", "/v1/chat/completions") => {
+            let request = match serde_json::from_slice(&body) {
+                Ok(v) => v,
+                Err(err) => {
+                    return write_error(&mut writer, 400, &format!("Invalid request, {err}"))
+                }
+            };
+            chat_completions(&mut writer, config, request)
+        }
+        _ => write_error(
+            &mut writer,
+            404,
+            &format!("Unknown endpoint {method} {path}"),
+        ),
+    }
+}
+
+fn chat_completions(
+    writer: &mut TcpStream,
+    config: &SharedConfig,
+    request: ChatCompletionsRequest,
+) -> Result<()> {
+    let stream = request.stream;
+    let (config, input) = match prepare_config(config, request) {
+        Ok(v) => v,
+        Err(err) => return write_error(writer, 400, &format!("{err:#}")),
+    };
+    let client = match init_client(config.clone()) {
+        Ok(v) => v,
+        Err(err) => return write_error(writer, 500, &format!("{err:#}")),
+    };
+    let completion = Completion::new(&config.read().model_info.stringify());
+    // A reply filter needs the whole reply, which is then sent as a single chunk
+    let filter = config.read().reply_filter.is_some();
+    if !stream || filter {
+        let ret = client
+            .send_message(&input, AbortSignal::new())
+            .and_then(|v| config.read().filter_reply(&v));
+        if !stream {
+            return match ret {
+                Ok(output) => write_json(writer, 200, &completion.message(&output)),
+                Err(err) => write_error(writer, 502, &format!("{err:#}")),
+            };
+        }
+        write_event_stream_head(writer, &completion)?;
+        match ret {
+            Ok(output) => {
+                write_event(
+                    writer,
+                    &completion.chunk(json!({ "content": output }), None),
+                )?;
+                write_event(writer, &completion.chunk(json!({}), Some("stop")))?;
+            }
+            Err(err) => write_stream_error(writer, &err)?,
+        }
+        write!(writer, "data: [DONE]\n\n")?;
+        return Ok(());
+    }
+    write_event_stream_head(writer, &completion)?;
+    let (tx, rx) = unbounded();
+    let abort = AbortSignal::new();
+    let flush_interval = Duration::from_millis(config.read().stream_flush_interval);
+    let mut handler = ReplyStreamHandler::new(tx, abort.clone(), false, false, flush_interval);
+    let mut event_writer = writer.try_clone()?;
+    let forward = {
+        let completion = completion.clone();
+        spawn(move || {
+            for event in rx {
+                match event {
+                    ReplyStreamEvent::Text(text) => {
+                        let chunk = completion.chunk(json!({ "content": text }), None);
+                        // The caller went away, stop the request as well
+                        if write_event(&mut event_writer, &chunk).is_err() {
+                            abort.set_ctrlc();
+                            break;
+                        }
+                    }
+                    ReplyStreamEvent::Footer(_) => {}
+                    ReplyStreamEvent::Done => break,
+                }
+            }
+        })
+    };
+    let ret = client.send_message_streaming(&input, &mut handler);
+    drop(handler);
+    let _ = forward.join();
+    match ret {
+        Ok(_) => write_event(writer, &completion.chunk(json!({}), Some("stop")))?,
+        Err(err) => write_stream_error(writer, &err)?,
+    }
+    write!(writer, "data: [DONE]\n\n")?;
+    Ok(())
+}
+
+fn write_event_stream_head(writer: &mut TcpStream, completion: &Completion) -> Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )?;
+    write_event(
+        writer,
+        &completion.chunk(json!({ "role": "assistant" }), None),
+    )
+}
+
+fn write_stream_error(writer: &mut TcpStream, err: &anyhow::Error) -> Result<()> {
+    let error = json!({ "error": { "message": format!("{err:#}"), "type": "aichat_error" } });
+    write_event(writer, &error)
+}
+
+/// Apply the request to a copy of the config, returning it along with the input
+fn prepare_config(
+    config: &SharedConfig,
+    request: ChatCompletionsRequest,
+) -> Result<(SharedConfig, String)> {
+    let mut config = config.read().clone();
+    config.session = None;
+    if let Some(model) = request.model.as_deref() {
+        // Tools often send a fixed name such as `gpt-3.5-turbo`, which keeps the configured model
+        if list_models(&config).iter().any(|v| v.stringify() == model) {
+            config.set_model(model)?;
+        }
+    }
+    if request.temperature.is_some() {
+        config.temperature = request.temperature;
+    }
+    if request.top_p.is_some() {
+        config.top_p = request.top_p;
+    }
+    if request.max_tokens.is_some() {
+        config.set_max_tokens(request.max_tokens)?;
+    }
+    match request.stop {
+        Some(Value::String(v)) => config.stop = vec![v],
+        Some(Value::Array(v)) => {
+            config.stop = v
+                .iter()
+                .filter_map(|v| v.as_str().map(|v| v.to_string()))
+                .collect()
+        }
+        _ => {}
+    }
+    let mut messages = request.messages;
+    let input = match messages.pop() {
+        Some(Message {
+            role: MessageRole::User,
+            content,
+        }) => content,
+        _ => bail!("The last message must be from the user"),
+    };
+    config.set_context(messages);
+    Ok((Arc::new(RwLock::new(config)), input))
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> Result<(String, String, Vec<u8>)> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => bail!("Invalid request line"),
+    };
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .with_context(|| "Invalid Content-Length")?;
+            }
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        bail!("The request body exceeds {MAX_BODY_SIZE} bytes");
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok((method, path, body))
+}
+
+fn write_json(writer: &mut TcpStream, status: u16, value: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        502 => "Bad Gateway",
+        _ => "Internal Server Error",
+    };
+    let body = value.to_string();
+    write!(
+        writer,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+fn write_error(writer: &mut TcpStream, status: u16, message: &str) -> Result<()> {
+    let error = json!({ "error": { "message": message, "type": "aichat_error" } });
+    write_json(writer, status, &error)
+}
+
+fn write_event(writer: &mut TcpStream, value: &Value) -> Result<()> {
+    write!(writer, "data: {value}\n\n")?;
+    writer.flush()?;
+    Ok(())
+}
@@ -1,11 +1,22 @@
+mod diff;
 mod split_line;
 mod tiktoken;
 
+pub use self::diff::{diff_lines, DiffLine};
 pub use self::split_line::*;
 pub use self::tiktoken::cl100k_base_singleton;
 
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
 use chrono::prelude::*;
-use std::io::{stdout, Write};
+use std::{
+    env,
+    fs::{read, read_to_string, remove_file, rename, write, File},
+    io::{self, stdout, Write},
+    path::Path,
+    process::{self, Command, Stdio},
+    thread,
+};
 
 #[macro_export]
 macro_rules! print_now {
@@ -19,6 +30,38 @@ pub fn print_now<T: ToString>(text: &T) {
     let _ = stdout().flush();
 }
 
+/// Build the OSC 52 escape sequence that asks the terminal to set the clipboard
+pub fn osc52_sequence(text: &str) -> String {
+    let encoded = general_purpose::STANDARD.encode(text);
+    format!("\x1b]52;c;{encoded}\x07")
+}
+
+/// The MIME type of an image supported by vision models, told by the extension
+pub fn image_mime(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path)
+        .extension()?
+        .to_string_lossy()
+        .to_lowercase();
+    match extension.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Read an image as a `data:` URL
+pub fn image_data_url(path: &str) -> Result<String> {
+    let mime = match image_mime(path) {
+        Some(v) => v,
+        None => bail!("Unsupported image '{path}', expect png, jpeg, gif or webp"),
+    };
+    let data = read(path).with_context(|| format!("Unable to read image '{path}'"))?;
+    let encoded = general_purpose::STANDARD.encode(data);
+    Ok(format!("data:{mime};base64,{encoded}"))
+}
+
 pub fn now() -> String {
     let now = Local::now();
     now.to_rfc3339_opts(SecondsFormat::Secs, false)
@@ -46,6 +89,104 @@ pub fn count_tokens(text: &str) -> usize {
         .len()
 }
 
+/// Run the text-to-speech command with the text as its last argument, without waiting for it
+pub fn speak(command: &str, text: &str) -> Result<()> {
+    let mut parts = command.split_whitespace();
+    let program = parts.next().ok_or_else(|| anyhow!("Invalid tts_command"))?;
+    let mut child = Command::new(program)
+        .args(parts)
+        .arg(text)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to run '{command}'"))?;
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// Run the shell command with the text on its stdin and return its stdout
+pub fn pipe_text(command: &str, text: &str) -> Result<String> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut child = Command::new(shell)
+        .args([flag, command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run '{command}'"))?;
+    let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("No stdin"))?;
+    let input = text.to_string();
+    // Written from another thread so that a large reply can't block on a full stdout pipe
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+    if !output.status.success() {
+        bail!("'{command}' exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Write the file through a temp file renamed into place, an interrupted write leaves it untouched
+pub fn write_atomic(path: &Path, content: impl AsRef<[u8]>) -> io::Result<()> {
+    write_atomic_with(path, |file| file.write_all(content.as_ref()))
+}
+
+fn write_atomic_with(
+    path: &Path,
+    write_content: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{name}.{}.tmp", process::id()));
+    let ret = File::create(&temp_path)
+        .and_then(|mut file| {
+            // Keep the mode of the file replaced, the config holds API keys
+            if let Ok(metadata) = path.metadata() {
+                file.set_permissions(metadata.permissions())?;
+            }
+            write_content(&mut file)?;
+            file.sync_all()
+        })
+        .and_then(|_| rename(&temp_path, path));
+    if ret.is_err() {
+        let _ = remove_file(&temp_path);
+    }
+    ret
+}
+
+/// Open the text in the user's editor and return the saved content
+pub fn edit_text(text: &str) -> Result<String> {
+    let editor = env::var("EDITOR")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .unwrap_or_else(|| {
+            if cfg!(windows) {
+                "notepad".into()
+            } else {
+                "vi".into()
+            }
+        });
+    let path = env::temp_dir().join(format!("{}-{}.md", env!("CARGO_CRATE_NAME"), process::id()));
+    write(&path, text).with_context(|| format!("Failed to write {}", path.display()))?;
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().ok_or_else(|| anyhow!("Invalid editor"))?;
+    let status = Command::new(program)
+        .args(parts)
+        .arg(&path)
+        .status()
+        .with_context(|| format!("Failed to run editor `{editor}`"));
+    let content = read_to_string(&path);
+    let _ = remove_file(&path);
+    let status = status?;
+    if !status.success() {
+        bail!("Editor `{editor}` exited with {status}");
+    }
+    content.with_context(|| format!("Failed to read {}", path.display()))
+}
+
 pub fn light_theme_from_colorfgbg(colorfgbg: &str) -> Option<bool> {
     let parts: Vec<_> = colorfgbg.split(';').collect();
     let bg = match parts.len() {
@@ -63,3 +204,195 @@ pub fn light_theme_from_colorfgbg(colorfgbg: &str) -> Option<bool> {
     let light = v > 128.0;
     Some(light)
 }
+
+/// Split `path:start-end` into the path and its 1-based inclusive line range
+pub fn parse_file_range(spec: &str) -> (&str, Option<(usize, usize)>) {
+    if let Some((path, range)) = spec.rsplit_once(':') {
+        if let Some((start, end)) = range.split_once('-') {
+            if let (Ok(start), Ok(end)) = (start.parse(), end.parse()) {
+                if !path.is_empty() {
+                    return (path, Some((start, end)));
+                }
+            }
+        }
+    }
+    (spec, None)
+}
+
+/// Read a file, or only the lines of a `path:start-end` spec
+pub fn read_file_range(spec: &str) -> Result<(String, String)> {
+    let (path, range) = parse_file_range(spec);
+    let contents = read_to_string(path).with_context(|| format!("Unable to read file '{path}'"))?;
+    let (start, end) = match range {
+        Some(v) => v,
+        None => return Ok((path.to_string(), contents)),
+    };
+    let lines: Vec<&str> = contents.lines().collect();
+    if start == 0 || start > end {
+        bail!("Invalid line range '{start}-{end}', the start must be between 1 and the end");
+    }
+    if start > lines.len() {
+        bail!(
+            "Invalid line range '{start}-{end}', '{path}' has only {} lines",
+            lines.len()
+        );
+    }
+    let end = end.min(lines.len());
+    let contents = lines[start - 1..end].join("\n");
+    Ok((format!("{path} (lines {start}-{end})"), contents))
+}
+
+/// Replace every `${NAME}` with the value given by `lookup`, failing on unset names
+pub fn expand_env_vars(text: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+        let tail = &rest[start + 2..];
+        let end = tail
+            .find('}')
+            .ok_or_else(|| anyhow!("Unclosed '${{' in '{text}'"))?;
+        let name = &tail[..end];
+        match lookup(name) {
+            Some(value) => output.push_str(&value),
+            None => bail!("Environment variable `{name}` is not set"),
+        }
+        rest = &tail[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// The number of single-character edits that turn `a` into `b`
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let value = (diagonal + usize::from(ca != *cb))
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            diagonal = row[j + 1];
+            row[j + 1] = value;
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidates within a few edits of `name`, the closest first
+pub fn closest_matches<'a>(name: &str, candidates: &[&'a str]) -> Vec<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(2);
+    let mut matches: Vec<(usize, &str)> = candidates
+        .iter()
+        .map(|v| (edit_distance(name, v), *v))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    matches.sort_by_key(|(distance, _)| *distance);
+    matches.into_iter().map(|(_, v)| v).collect()
+}
+
+/// Count the words of a text, each CJK character counts as one since they are not spaced
+pub fn count_words(text: &str) -> usize {
+    let is_cjk = |c: char| {
+        matches!(c as u32,
+            0x3040..=0x30FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xAC00..=0xD7AF | 0xF900..=0xFAFF)
+    };
+    text.split_whitespace()
+        .map(|word| {
+            let cjk = word.chars().filter(|c| is_cjk(*c)).count();
+            let rest = word
+                .split(is_cjk)
+                .filter(|v| v.chars().any(|c| c.is_alphanumeric()))
+                .count();
+            cjk + rest
+        })
+        .sum()
+}
+
+/// Hide a secret except for a few characters at both ends
+pub fn redact(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() <= 12 {
+        return "***".into();
+    }
+    let head: String = chars[..3].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{head}***{tail}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_mime() {
+        assert_eq!(image_mime("a/b.PNG"), Some("image/png"));
+        assert_eq!(image_mime("b.jpg"), Some("image/jpeg"));
+        assert_eq!(image_mime("b.txt"), None);
+        assert_eq!(image_mime("png"), None);
+    }
+
+    #[test]
+    fn test_parse_file_range() {
+        assert_eq!(parse_file_range("src/main.rs"), ("src/main.rs", None));
+        assert_eq!(
+            parse_file_range("src/main.rs:40-80"),
+            ("src/main.rs", Some((40, 80)))
+        );
+        assert_eq!(parse_file_range("C:\\a.txt"), ("C:\\a.txt", None));
+        assert_eq!(parse_file_range("a.txt:x-1"), ("a.txt:x-1", None));
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        let lookup = |name: &str| (name == "KEY").then(|| "sk-1".to_string());
+        assert_eq!(expand_env_vars("${KEY}", lookup).unwrap(), "sk-1");
+        assert_eq!(expand_env_vars("a${KEY}b$c", lookup).unwrap(), "ask-1b$c");
+        assert!(expand_env_vars("${NOPE}", lookup).is_err());
+        assert!(expand_env_vars("${KEY", lookup).is_err());
+    }
+
+    #[test]
+    fn test_closest_matches() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        let candidates = ["shell", "coder", "emoji", "shell-pro"];
+        assert_eq!(closest_matches("shel", &candidates), vec!["shell"]);
+        assert_eq!(closest_matches("codr", &candidates), vec!["coder"]);
+        assert!(closest_matches("translate", &candidates).is_empty());
+    }
+
+    #[test]
+    fn test_count_words() {
+        assert_eq!(count_words("Hello, world! It's  fine - ok"), 5);
+        assert_eq!(count_words("使用Rust编写"), 5);
+        assert_eq!(count_words(""), 0);
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact("short"), "***");
+        assert_eq!(redact("sk-abcdefghijklmnop"), "sk-***mnop");
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = env::temp_dir().join(format!("aichat-test-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("session.yaml");
+        write_atomic(&path, "old").unwrap();
+        // Cut off in the middle of writing
+        let ret = write_atomic_with(&path, |file| {
+            file.write_all(b"ne")?;
+            Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"))
+        });
+        assert!(ret.is_err());
+        assert_eq!(read_to_string(&path).unwrap(), "old");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        write_atomic(&path, "new").unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), "new");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}